@@ -0,0 +1,114 @@
+#!/bin/sh
+#
+# Conformance test runner for weebasic
+#
+# Each tests/NAME.bas program, or tests/NAME.md literate script, is run and
+# its stdout is compared against tests/NAME.out, if it exists, otherwise no
+# output is expected.
+# If tests/NAME.in exists, it is fed to the program on stdin.
+# If tests/NAME.status exists, the exit status must match its contents,
+# otherwise the program is expected to exit with status 0.
+# If tests/NAME.args exists, its contents are passed as extra options.
+# If tests/NAME.err exists, it is a snapshot of the expected diagnostics on
+# stderr. Snapshots can be updated after an intended change with --bless.
+# Results are reported in TAP format, or as JUnit XML with --junit.
+#
+# The interpreter under test can be changed with the WEEBASIC environment
+# variable, so that alternative backends can be validated against this suite:
+#
+#   WEEBASIC=./my_backend ./run_tests.sh
+#
+
+WEEBASIC=${WEEBASIC:-./weebasic}
+TEST_DIR=tests
+FORMAT=tap
+BLESS=
+
+# Run from the repository root so test file paths are stable
+cd "$(dirname "$0")" || exit 1
+
+for arg in "$@"
+do
+    case "$arg" in
+        --junit) FORMAT=junit ;;
+        --bless) BLESS=1 ;;
+    esac
+done
+
+tests=$(ls "$TEST_DIR"/*.bas "$TEST_DIR"/*.md 2>/dev/null)
+num_tests=$(echo "$tests" | wc -l)
+num_failed=0
+test_num=0
+actual=$(mktemp)
+actual_err=$(mktemp)
+results=$(mktemp)
+trap 'rm -f "$actual" "$actual_err" "$results"' EXIT
+
+for test_file in $tests
+do
+    test_num=$((test_num + 1))
+    name=$(basename "$test_file")
+    name=${name%.*}
+    input_file="$TEST_DIR/$name.in"
+    expected_file="$TEST_DIR/$name.out"
+    status_file="$TEST_DIR/$name.status"
+    args_file="$TEST_DIR/$name.args"
+    err_file="$TEST_DIR/$name.err"
+    expected_status=0
+    args=
+
+    if [ ! -f "$input_file" ]; then
+        input_file=/dev/null
+    fi
+
+    if [ ! -f "$expected_file" ]; then
+        expected_file=/dev/null
+    fi
+
+    if [ -f "$status_file" ]; then
+        expected_status=$(cat "$status_file")
+    fi
+
+    if [ -f "$args_file" ]; then
+        args=$(cat "$args_file")
+    fi
+
+    # shellcheck disable=SC2086
+    RUST_BACKTRACE=0 "$WEEBASIC" $args "$test_file" < "$input_file" > "$actual" 2> "$actual_err"
+    status=$?
+
+    if [ -f "$err_file" ] && [ -n "$BLESS" ]; then
+        cp "$actual_err" "$err_file"
+    fi
+
+    if [ ! -f "$err_file" ]; then
+        err_file=$actual_err
+    fi
+
+    if cmp -s "$actual" "$expected_file" && cmp -s "$actual_err" "$err_file" && [ "$status" -eq "$expected_status" ]; then
+        echo "ok $test_num - $name" >> "$results"
+    else
+        num_failed=$((num_failed + 1))
+        echo "not ok $test_num - $name" >> "$results"
+    fi
+done
+
+if [ "$FORMAT" = "tap" ]; then
+    echo "1..$num_tests"
+    cat "$results"
+else
+    echo '<?xml version="1.0" encoding="UTF-8"?>'
+    echo "<testsuite name=\"weebasic\" tests=\"$num_tests\" failures=\"$num_failed\">"
+    while read -r status rest
+    do
+        name=${rest#* - }
+        if [ "$status" = "ok" ]; then
+            echo "  <testcase name=\"$name\"/>"
+        else
+            echo "  <testcase name=\"$name\"><failure message=\"output mismatch\"/></testcase>"
+        fi
+    done < "$results"
+    echo "</testsuite>"
+fi
+
+[ "$num_failed" -eq 0 ]
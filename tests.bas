@@ -40,6 +40,425 @@ if 1 then begin
     end
 end
 
-# TODO: there is no ability to parse or print strings yet
-#print "All tests OK"
-print 1
+# Evaluating an expression and discarding its value
+_ = 1 + 2
+_ = (1 + 1) == 2
+
+# If/else statements
+let e = 0
+if e then assert 0 else assert 1
+if e == 0 then assert 1 else assert 0
+
+# Functions with parameters and return values
+fun sub3(a, b, c)
+    return (a - b) - c
+end
+assert sub3(10, 3, 2) == 5
+assert sub3(1, 1, 0) == 0
+
+# Multiplication and division
+assert (3 * 4) == 12
+assert (7 / 2) == 3
+assert ((2 * 3) / 3) == 2
+assert ((0 - 7) / 2) == (0 - 3)
+
+# Modulo
+assert (7 % 3) == 1
+assert (9 % 3) == 0
+assert ((0 - 7) % 3) == (0 - 1)
+
+# Ordered comparisons
+assert 2 > 1
+assert (1 > 2) == false
+assert 2 >= 2
+assert 3 >= 2
+assert (1 >= 2) == false
+assert 2 <= 2
+assert 1 <= 2
+assert (3 <= 2) == false
+
+# Inequality
+assert 1 != 2
+assert (2 != 2) == false
+assert 1 <> 2
+assert (2 <> 2) == false
+
+# Logical and/or
+assert 1 and 1
+assert (1 and 0) == 0
+assert (0 and 1) == 0
+assert 1 or 0
+assert 0 or 1
+assert (0 or 0) == 0
+assert 1 < 2 and 2 < 3
+assert 2 < 1 or 1 < 2
+assert 0 or 0 or 1
+
+# Logical not
+assert not 0
+assert (not 1) == false
+assert (not 7) == false
+assert not not 5
+assert not (1 > 2)
+assert not 0 and not 0
+
+# Unary minus
+assert -3 == (0 - 3)
+let neg = 5
+assert -neg == (0 - 5)
+assert --neg == 5
+assert (2 - -3) == 5
+assert -(1 + 2) == (0 - 3)
+assert (-7 / 2) == -3
+
+# Operator precedence and associativity
+assert 1 + 2 + 3 == 6
+assert 2 + 3 * 4 == 14
+assert 10 - 3 - 2 == 5
+assert 100 / 10 / 5 == 2
+assert 2 * 3 + 4 * 5 == 26
+assert 7 % 4 * 2 == 6
+assert 1 + 1 == 2 and 2 * 2 == 4
+assert 0 and 0 or 1
+assert 1 or 0 and 0
+assert 1 < 2 == true
+assert -2 * 3 == -6
+
+# Exponentiation
+assert 2 ^ 10 == 1024
+assert 5 ^ 0 == 1
+assert 2 * 3 ^ 2 == 18
+assert 2 ^ 3 ^ 2 == 512
+assert -2 ^ 3 == -8
+assert -2 ^ 2 == -4
+assert (-2) ^ 2 == 4
+assert_eq -2 ^ 2 * 3, -12
+
+# String literals
+assert "abc"
+let greeting = "hello # not a comment"
+assert greeting
+
+# Three-way comparison
+assert cmp(1, 2) == -1
+assert cmp(2, 2) == 0
+assert cmp(3, 2) == 1
+assert cmp("abc", "abd") == -1
+assert cmp("b", "a") == 1
+assert cmp("", "") == 0
+
+# String concatenation
+assert cmp("foo" + "bar", "foobar") == 0
+assert cmp("x = " + 3, "x = 3") == 0
+assert cmp(1 + 2 + "!", "3!") == 0
+assert cmp("" + -5, "-5") == 0
+
+# Asserting that values are equal
+assert_eq 1 + 1, 2
+assert_eq "a" + "b", "ab"
+assert_eq cmp(1, 2), -1
+
+# String comparisons
+assert "abc" == "abc"
+assert "abc" != "abd"
+assert "abc" < "abd"
+assert "b" > "abc"
+assert "" < "a"
+assert "a" <= "a" and "a" >= "a"
+
+# String builtins
+assert_eq len(""), 0
+assert_eq len("hello"), 5
+assert_eq substr("hello", 1, 3), "ell"
+assert_eq substr("hello", 3, 10), "lo"
+assert_eq substr("hello", 7, 2), ""
+assert_eq find("hello", "l"), 2
+assert_eq find("hello", "lo"), 3
+assert_eq find("hello", "z"), -1
+assert_eq find("hello", ""), 0
+
+# Floating-point numbers, mixed with integers they give floats
+assert_eq 1.5 + 1.5, 3.0
+assert_eq 1 + 0.5, 1.5
+assert_eq 7.0 / 2, 3.5
+assert_eq 7 / 2, 3
+assert_eq 2.0 ^ 3, 8.0
+assert_eq 2 ^ -1.0, 0.5
+assert_eq -0.25 * 4, -1
+assert 0.1 < 0.2 and 2 > 1.5
+assert_eq int(3.9), 3
+assert_eq int(-3.9), -3
+assert_eq float(2), 2.0
+assert_eq "x = " + 2.5, "x = 2.5"
+assert not 0.0
+
+# Booleans, produced by comparisons
+assert true
+assert not false
+assert_eq 1 < 2, true
+assert_eq 2 == 3, false
+assert_eq not 0, true
+assert true != false
+assert_eq int(true), 1
+assert_eq int(false), 0
+assert_eq "ok: " + true, "ok: true"
+
+# Arrays
+let arr = [1, 2, 3]
+assert_eq arr[0], 1
+assert_eq arr[2], 3
+assert_eq arr[1 + 1] * 2, 6
+arr[1] = 20
+assert_eq arr[1], 20
+assert_eq arr, [1, 20, 3]
+assert_eq [], []
+let grid = [[1, 2], [3, 4]]
+grid[1][0] = 30
+assert_eq grid[1][0], 30
+assert_eq -arr[0], -1
+
+# Function references and indirect calls
+fun double(x)
+    return x * 2
+end
+fun apply(f, x)
+    return f(x)
+end
+let dbl = @double
+assert_eq dbl(3), 6
+assert_eq apply(@double, 5), 10
+assert_eq [@double][0] == dbl, true
+
+# Array builtins
+let stack = []
+assert_eq len(stack), 0
+assert_eq push(stack, 1), 1
+push(stack, 2)
+push(stack, "three")
+assert_eq len(stack), 3
+assert_eq pop(stack), "three"
+assert_eq pop(stack), 2
+assert_eq stack, [1]
+
+# Named arguments
+fun point(x, y, z)
+    return [x, y, z]
+end
+assert_eq point(1, 2, 3), [1, 2, 3]
+assert_eq point(x: 1, y: 2, z: 3), [1, 2, 3]
+assert_eq point(z: 3, x: 1, y: 2), [1, 2, 3]
+assert_eq point(1, z: 3, y: 2), [1, 2, 3]
+
+# Variadic functions
+fun count_args(first, rest...)
+    return len(rest) + 1
+end
+assert_eq count_args(1), 1
+assert_eq count_args(1, 2, 3), 3
+fun collect(items...)
+    return items
+end
+assert_eq collect(), []
+assert_eq collect("a", "b"), ["a", "b"]
+let collect_ref = @collect
+assert_eq collect_ref(1, 2), [1, 2]
+
+# Assignment to declared variables
+let counter = 1
+counter = counter + 1
+assert_eq counter, 2
+let letter = "a"
+letter = letter + "b"
+assert_eq letter, "ab"
+let printed = 0
+printed = 1
+assert_eq printed, 1
+
+# Compound assignment
+let acc = 10
+acc += 5
+acc -= 3
+acc *= 2
+assert_eq acc, 24
+let words = "a"
+words += "b" + "c"
+assert_eq words, "abc"
+
+# Breaking out of loops
+let found = 0
+for i = 1 to 100
+    if i * i > 50 then break
+    found = i
+end
+assert_eq found, 7
+let spins = 0
+repeat 10 times
+    spins += 1
+    if spins == 4 then begin
+        break
+    end
+end
+assert_eq spins, 4
+let dead_breaks = 0
+for i = 1 to 3
+    if false then break
+    dead_breaks += 1
+end
+assert_eq dead_breaks, 3
+
+# Skipping to the next loop iteration
+let odd_sum = 0
+for i = 1 to 10
+    if i % 2 == 0 then continue
+    odd_sum += i
+end
+assert_eq odd_sum, 25
+let skipped = 0
+let rounds = 0
+repeat 5 times
+    rounds += 1
+    if rounds < 3 then continue
+    skipped += 1
+end
+assert_eq skipped, 3
+
+# Goto and labels
+let jumps = 0
+label again:
+jumps += 1
+if jumps < 3 then goto again
+assert_eq jumps, 3
+goto skip
+assert false
+label skip:
+fun first_even(a, b)
+    if a % 2 == 0 then goto found
+    return b
+    label found:
+    return a
+end
+assert_eq first_even(4, 6), 4
+assert_eq first_even(3, 6), 6
+
+# Gosub subroutines
+let calls = 0
+gosub bump
+gosub bump
+assert_eq calls, 2
+goto after_bump
+label bump:
+calls += 1
+return
+label after_bump:
+
+# Counted loops step their variable past the limit
+let steps = 0
+for i = 1 to 10 step 3
+    steps += 1
+end
+assert_eq steps, 4
+assert_eq i, 13
+let halves = []
+for h = 0.5 to 2
+    push(halves, h)
+end
+assert_eq halves, [0.5, 1.5]
+for never = 5 to 1
+    assert false
+end
+assert_eq never, 5
+
+# Select case
+fun describe(n)
+    select n
+    case 0
+        return "zero"
+    case 1, 2, 3
+        return "small"
+    case -1
+        return "minus one"
+    case else
+        return "other"
+    end
+end
+assert_eq describe(0), "zero"
+assert_eq describe(2), "small"
+assert_eq describe(-1), "minus one"
+assert_eq describe(9), "other"
+let matched = 0
+select "b"
+case "a"
+    matched = 1
+case "b"
+    matched = 2
+end
+assert_eq matched, 2
+
+# Do until loops run their body at least once
+let tries = 0
+do
+    tries += 1
+until tries >= 3
+assert_eq tries, 3
+let once = 0
+do
+    once += 1
+until true
+assert_eq once, 1
+let evens = 0
+let k = 0
+do
+    k += 1
+    if k % 2 == 1 then continue
+    if k > 6 then break
+    evens += 1
+until k >= 100
+assert_eq evens, 3
+
+# A return without a value in a function returns none
+fun bare_return(x)
+    if x == 0 then begin return end
+    if x == 1 then return else return x
+    return
+end
+assert_eq "" + bare_return(0), "none"
+assert_eq "" + bare_return(1), "none"
+assert_eq bare_return(2), 2
+fun bare_return_last()
+    return
+end
+assert_eq "" + bare_return_last(), "none"
+
+# Arrays can contain themselves
+let cyclic = [1]
+push(cyclic, cyclic)
+assert_eq cyclic, cyclic
+assert_eq "" + cyclic, "[1, [...]]"
+let cyclic2 = [1]
+push(cyclic2, cyclic2)
+assert cyclic == cyclic2
+assert [1, [2]] == [1, [2]]
+assert [1] != [2]
+assert 1 != "1"
+
+# Float literals with exponents, which floats print back as
+assert_eq 1e3, 1000.0
+assert_eq 2.5e-3, 0.0025
+assert_eq 1E+2, 100.0
+assert_eq "" + 1e23, "1e23"
+assert_eq "" + 1e-7, "1e-7"
+let nan = 0.0 / 0.0
+assert nan != nan
+assert not (nan == nan)
+
+# Integer arithmetic up to the bounds of 64 bits doesn't overflow
+let int_max = 9223372036854775807
+let int_min = -int_max - 1
+assert_eq int_max - 1 + 1, int_max
+assert_eq int_min + 1 - 1, int_min
+assert_eq int_min / 2 * 2, int_min
+assert_eq -(int_min + 1), int_max
+assert_eq 2 ^ 62 - 1 + 2 ^ 62, int_max
+assert_eq int_min % -1, 0
+
+print "All tests OK"
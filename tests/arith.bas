@@ -0,0 +1,6 @@
+# Integer arithmetic
+print 1 + 2
+print 10 - 3
+print (4 + 5) - 2
+let x = 7
+print x + x
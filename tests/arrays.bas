@@ -0,0 +1,7 @@
+# Arrays are shared by reference
+let a = [1, 2, 3]
+let b = a
+b[0] = 10
+print a
+print [a[0] + 1, "two", [3.0]]
+print a[3]
@@ -0,0 +1,6 @@
+# A failing assert_eq reports both operands
+let x = 2
+assert_eq x + 1, 3
+print 1
+assert_eq x * 2, 5
+print 2
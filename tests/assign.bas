@@ -0,0 +1,23 @@
+# Variables can be updated after they are declared
+let total = 0
+for i = 1 to 10
+    total = total + i
+end
+print total
+
+let a = 0
+let b = 1
+repeat 8 times
+    let next = a + b
+    a = b
+    b = next
+end
+print a
+
+let countdown = 3
+let log = ""
+repeat countdown times
+    log += countdown + " "
+    countdown -= 1
+end
+print log
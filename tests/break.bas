@@ -0,0 +1,20 @@
+# Break only exits the innermost loop
+for i = 1 to 3
+    for j = 1 to 3
+        if j > i then break
+        print i + "," + j
+    end
+end
+
+let n = 0
+repeat 100 times
+    n += 1
+    if n == 3 then break
+end
+print n
+
+# Continue skips to the next iteration, still stepping the loop variable
+for i = 10 to 1 step -3
+    if i == 7 then continue
+    print i
+end
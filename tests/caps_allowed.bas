@@ -0,0 +1,3 @@
+# Host operations run when their capability is allowed
+let x = read_int
+print x
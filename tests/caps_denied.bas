@@ -0,0 +1,4 @@
+# Host operations fail when their capability is not allowed
+print 1
+let x = read_int
+print x
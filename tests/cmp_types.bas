@@ -0,0 +1,3 @@
+# Only values of the same type can be compared
+print cmp(1, 2)
+print cmp(1, "2")
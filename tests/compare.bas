@@ -0,0 +1,5 @@
+# Comparisons produce true or false
+print 1 == 1
+print 1 == 2
+print 1 < 2
+print 2 < 1
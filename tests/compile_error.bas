@@ -0,0 +1,3 @@
+# Syntax errors are compile errors, nothing gets run
+print 1
+print )
@@ -0,0 +1,8 @@
+# Constant conditions are resolved at compile time
+if 0 then print 1
+if 1 then print 2
+if 0 then begin
+    print 3
+    print 4
+end
+if (1) then print 5
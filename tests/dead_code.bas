@@ -0,0 +1,14 @@
+# Unreachable code is dropped, with a warning pointing at it
+if 0 then print "never"
+if 1 then print "always" else print "never"
+
+# Functions declared in unreachable code are dropped too
+if false then begin
+    fun helper()
+        return 1
+    end
+end
+fun helper()
+    return 2
+end
+print helper()
@@ -0,0 +1,16 @@
+# Deeply nested arrays can be built and freed, but not printed
+let a = []
+for i = 1 to 100000
+    a = [a]
+end
+let b = a
+a = 0
+print len(b)
+b = 0
+print "freed"
+
+let c = []
+for i = 1 to 100000
+    c = [c]
+end
+print c
@@ -0,0 +1,8 @@
+# Comparing deeply nested arrays stops at the nesting limit
+let c = []
+let d = []
+for i = 1 to 10000
+    c = [c]
+    d = [d]
+end
+print c == d
@@ -0,0 +1,4 @@
+# Reading a value and discarding it
+_ = read_int
+let x = read_int
+print x
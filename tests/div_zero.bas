@@ -0,0 +1,4 @@
+# Division by zero is a run-time error
+let d = 0
+print 10 / 5
+print 10 / d
@@ -0,0 +1,7 @@
+# Ask again until the input is valid
+let guess = 0
+do
+    print "guess a number between 1 and 10"
+    guess = read_int
+until guess >= 1 and guess <= 10
+print "got " + guess
@@ -0,0 +1,17 @@
+# If statements with an else clause
+let x = 3
+if x < 5 then print 1 else print 2
+if 5 < x then print 3 else print 4
+
+if x == 3 then begin
+    print 5
+end else begin
+    print 6
+end
+
+# Nested if/else, the else goes with the closest if
+if x == 3 then if x < 2 then print 7 else print 8
+
+# Constant conditions
+if 1 then print 9 else print 10
+if 0 then print 11 else print 12
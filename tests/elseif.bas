@@ -0,0 +1,17 @@
+# Chains of elseif clauses
+for x = 1 to 4
+    if x == 1 then print 10
+    elseif x == 2 then print 20
+    elseif x == 3 then begin
+        print 30
+    end
+    else print 40
+end
+
+# Chains without a final else
+for y = 1 to 3
+    if y == 1 then print 1 elseif y == 2 then print 2
+end
+
+# Constant conditions in a chain
+if 0 then print 5 elseif 1 then print 6 else print 7
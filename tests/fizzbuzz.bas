@@ -0,0 +1,7 @@
+# FizzBuzz, with 3 for Fizz, 5 for Buzz and 15 for FizzBuzz
+for i = 1 to 15
+    if (i % 15) == 0 then print 15
+    elseif (i % 3) == 0 then print 3
+    elseif (i % 5) == 0 then print 5
+    else print i
+end
@@ -0,0 +1,7 @@
+# Floats are printed with a decimal point, to tell them from integers
+print 1.5
+print 2.0
+print 10 / 4
+print 10 / 4.0
+print 1.0 / 3
+print 1.0 / 0
@@ -0,0 +1,6 @@
+# Floats can be printed with another decimal point, and grouped digits
+print 1234567.5
+print -1234.25
+print 0.5
+print 1e23
+print 1234567
@@ -0,0 +1,26 @@
+# Counted for loops
+for i = 1 to 3
+    print i
+end
+
+for j = 0 to 10 step 4
+    print j
+end
+
+# The limit is inclusive, and loops can be empty
+for k = 5 to 4
+    print k
+end
+
+# Nested loops
+let n = 2
+for a = 1 to n
+    for b = a to n
+        trace a + b
+    end
+end
+
+# Counting down with a negative step
+for c = 3 to 1 step -1
+    print c
+end
@@ -0,0 +1,15 @@
+# Loops whose counter would overflow stop at the bounds of integers
+for i = 9223372036854775806 to 9223372036854775807
+    print i
+end
+print "after: " + i
+
+for i = -9223372036854775807 to -9223372036854775807 - 1 step -1
+    print i
+end
+print "after: " + i
+
+for i = 9223372036854775805 to 9223372036854775807 step 2
+    print i
+end
+print "after: " + i
@@ -0,0 +1,23 @@
+# User-defined functions
+fun hello()
+    print 1
+end
+
+fun twice()
+    hello()
+    hello()
+end
+
+print 0
+twice()
+let x = hello()
+print x
+
+# Functions can be declared anywhere and are only run when called
+for i = 1 to 2
+    print i
+end
+fun bye()
+    print 99
+end
+bye()
@@ -0,0 +1,25 @@
+# Function parameters and return values
+fun add(a, b)
+    return a + b
+end
+
+fun fib(n)
+    if n < 2 then return n
+    return fib(n - 1) + fib(n - 2)
+end
+
+print add(3, 4)
+print fib(10)
+
+# Each call has its own local variables
+fun count_down(n)
+    let m = n - 1
+    if 0 < n then _ = count_down(m)
+    print n
+end
+count_down(2)
+
+# Function locals are separate from the top level
+let a = 100
+print add(1, 2)
+print a
@@ -0,0 +1,11 @@
+# Functions can be passed around by reference
+fun inc(x)
+    return x + 1
+end
+fun twice(f, x)
+    return f(f(x))
+end
+print twice(@inc, 5)
+let g = @inc
+print g(g(g(0)))
+print g(1, 2)
@@ -0,0 +1,15 @@
+# Subroutines in the classic BASIC style, sharing the top-level variables
+let n = 3
+gosub show
+n = 10
+gosub show
+return 0
+
+label show:
+print "n = " + n
+gosub square
+return
+
+label square:
+print "n^2 = " + n * n
+return
@@ -0,0 +1,4 @@
+# Falling through into a subroutine returns without a gosub
+label sub:
+print "in sub"
+return
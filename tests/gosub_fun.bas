@@ -0,0 +1,8 @@
+# Functions can't use gosub, a bare return in them returns none
+fun f(n)
+    gosub sub
+    return n
+    label sub:
+    return
+end
+print f(1)
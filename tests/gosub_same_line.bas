@@ -0,0 +1,11 @@
+# A bare return can be followed by the end of its block on the same line
+let n = 0
+gosub check
+n = 5
+gosub check
+return 0
+
+label check:
+if n == 0 then begin print "zero" return end
+print "n = " + n
+return
@@ -0,0 +1,9 @@
+# Low-level loop written with goto, like early BASIC
+let i = 1
+label top:
+if i > 3 then goto done
+print i
+i += 1
+goto top
+label done:
+print "done"
@@ -0,0 +1,8 @@
+# Conditional statements
+let x = 5
+if x < 10 then print 1
+if 10 < x then print 2
+if x == 5 then begin
+    print 3
+    if 1 then print 4
+end
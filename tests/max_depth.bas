@@ -0,0 +1,3 @@
+# The nesting limit can be lowered
+print ((((((1))))))
+print [[[[[[[[[[1]]]]]]]]]]
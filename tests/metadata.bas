@@ -0,0 +1,6 @@
+# Source location metadata
+print __line__
+
+print __file__
+let line = __line__
+print line
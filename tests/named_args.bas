@@ -0,0 +1,11 @@
+# Arguments are evaluated in source order, then passed by parameter name
+fun show(label, value)
+    print label + ": " + value
+    return 0
+end
+fun side(x)
+    print x
+    return x
+end
+show(value: side(1), label: side("first"))
+_ = show("second", value: 2)
@@ -0,0 +1,5 @@
+# Integer arithmetic that doesn't fit in 64 bits is an error
+let big = 3037000500
+print big - 1
+print (big - 1) * (big - 1)
+print big * big
@@ -0,0 +1,4 @@
+# Read integers from stdin
+let a = read_int
+let b = read_int
+print a + b
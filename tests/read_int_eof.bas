@@ -0,0 +1,3 @@
+# Running out of input is a run-time error
+print read_int
+print read_int
@@ -0,0 +1,6 @@
+# Integers can be entered with whitespace, a sign and underscores,
+# and invalid input is asked for again
+print read_int
+print read_int
+print read_int
+print read_int
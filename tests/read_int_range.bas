@@ -0,0 +1,2 @@
+# Integers that don't fit in 64 bits are a run-time error
+print read_int
@@ -0,0 +1,6 @@
+# Runaway recursion is a run-time error
+fun forever(n)
+    return forever(n + 1)
+end
+print 1
+print forever(0)
@@ -0,0 +1,33 @@
+# Repeating a statement a number of times
+repeat 3 times
+    print 7
+end
+
+# The count is evaluated once, and loops can be empty
+let n = 2
+repeat n + 1 times
+    let n2 = n * 10
+    print n2
+end
+
+repeat 0 times
+    print 0
+end
+
+repeat -1 times
+    print -1
+end
+
+# Nested loops
+repeat 2 times
+    repeat 2 times
+        print 1
+    end
+end
+
+# The times keyword can be left out
+let count = 0
+repeat 4
+    count += 1
+end
+print count
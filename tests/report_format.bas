@@ -0,0 +1,2 @@
+# Only JSON reports are supported
+print 1
@@ -0,0 +1,6 @@
+# Returning from the top level ends the program
+# and the value becomes the exit status
+let x = 3
+print x
+if x == 3 then return x + 4
+print 0
@@ -0,0 +1,3 @@
+# Program results must fit in an exit code
+print "before"
+return 256
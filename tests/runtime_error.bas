@@ -0,0 +1,4 @@
+# Failing asserts are run-time errors
+print 1
+assert 1 == 2
+print 2
@@ -0,0 +1,30 @@
+# Multi-way branch, the first matching case runs, then the select ends,
+# and case else runs when no other case matches
+for day = 0 to 7
+    select day % 7
+    case 0, 6
+        print day + ": weekend"
+    case 5
+        print day + ": friday"
+        print day + ": almost weekend"
+    case else
+        print day + ": weekday"
+    end
+end
+
+# Without an else, nothing runs if no case matches
+select 42
+case 1
+    print "one"
+end
+print "done"
+
+# An if without else can end a case, before case else
+let x = 5
+let y = 0
+select x
+case 1
+    if y then print "a"
+case else
+    print "other"
+end
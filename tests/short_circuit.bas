@@ -0,0 +1,11 @@
+# Logical operators only evaluate their RHS when needed
+fun side(x)
+    print x
+    return x
+end
+
+_ = side(0) and side(1)
+_ = side(2) and side(3)
+_ = side(4) or side(5)
+_ = side(0) or side(6)
+print 1 < 2 and 3
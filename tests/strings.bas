@@ -0,0 +1,12 @@
+# String literals, with escape sequences
+print "hello, world"
+print "tab\tseparated"
+print "two\nlines"
+print "a \"quoted\" word and a \\ backslash"
+let s = "# not a comment"
+print s
+
+# Strings can be joined, along with numbers
+let name = "world"
+print "hello, " + name + "!"
+print "answer: " + (6 * 7)
@@ -0,0 +1,5 @@
+# Calls must pass every parameter
+fun add(a, b)
+    return a + b
+end
+print add(1)
@@ -0,0 +1,3 @@
+# Assignment needs a declared variable
+let count = 0
+cuont = 1
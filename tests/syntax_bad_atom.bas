@@ -0,0 +1,2 @@
+# Not a valid expression
+let x = )
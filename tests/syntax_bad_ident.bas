@@ -0,0 +1,2 @@
+# Variable names must be identifiers
+let 1 = 2
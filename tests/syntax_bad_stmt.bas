@@ -0,0 +1,3 @@
+# Not a valid statement
+print 1
+end
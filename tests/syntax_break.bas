@@ -0,0 +1,5 @@
+# Break must be inside a loop
+for i = 1 to 3
+    print i
+end
+break
@@ -0,0 +1,9 @@
+# Case values must be constants
+let x = 1
+let y = 2
+select x
+case 1
+    print "one"
+case y
+    print "y"
+end
@@ -0,0 +1,4 @@
+# Continue must be inside a loop
+fun f(x)
+    continue
+end
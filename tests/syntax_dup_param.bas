@@ -0,0 +1,3 @@
+# Parameter names must be distinct
+fun f(a, a)
+end
@@ -0,0 +1,5 @@
+# Goto needs a label defined in the same function
+label finish:
+fun f()
+    goto finish
+end
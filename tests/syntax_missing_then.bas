@@ -0,0 +1,2 @@
+# An if statement needs a then
+if 1 print 1
@@ -0,0 +1,5 @@
+# Named arguments must match a parameter
+fun draw(x, y)
+    return 0
+end
+draw(x: 1, z: 2)
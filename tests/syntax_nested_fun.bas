@@ -0,0 +1,5 @@
+# No closures, functions are only declared at the top level
+fun outer()
+    fun inner()
+    end
+end
@@ -0,0 +1,5 @@
+# Positional arguments come first
+fun draw(x, y)
+    return 0
+end
+draw(y: 1, 2)
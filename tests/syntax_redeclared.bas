@@ -0,0 +1,3 @@
+# Variables can only be declared once
+let x = 1
+let x = 2
@@ -0,0 +1,4 @@
+# A for loop step of zero would never end
+for i = 1 to 10 step 0
+    print i
+end
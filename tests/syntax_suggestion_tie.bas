@@ -0,0 +1,6 @@
+# Equally close suggestions are picked by name
+let xe = 1
+let xb = 1
+let xd = 1
+let xa = 1
+print xc
@@ -0,0 +1,2 @@
+# Missing closing parenthesis
+print (1 + 2
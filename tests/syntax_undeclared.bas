@@ -0,0 +1,3 @@
+# Misspelled variable name
+let count = 1
+print coutn
@@ -0,0 +1,3 @@
+# Strings must be closed on the same line
+print "hello
+print 1
@@ -0,0 +1,5 @@
+# Variadic functions still need their fixed parameters
+fun log(level, parts...)
+    return len(parts)
+end
+_ = log()
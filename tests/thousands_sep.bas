@@ -0,0 +1,7 @@
+# Printing numbers with a thousands separator
+print 1
+print 999
+print 1000
+print 1234567
+print 0 - 1234567
+print 100000
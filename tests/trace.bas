@@ -0,0 +1,6 @@
+# Tracing expressions along with their values
+let x = 5
+let y = 7
+trace x + y
+trace (x - 1) == 4 # comment after the expression
+trace 3
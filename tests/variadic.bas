@@ -0,0 +1,12 @@
+# Extra arguments are collected into an array for the last parameter
+fun log(level, parts...)
+    print level + ": " + len(parts) + " parts"
+    for i = 0 to len(parts) - 1
+        print parts[i]
+    end
+    return 0
+end
+log("info", "starting", 42)
+log("debug")
+let warn = @log
+_ = warn("warn", [1, 2])
@@ -11,39 +11,161 @@
 
 #![allow(dead_code)]
 
+use std::any::Any;
 use std::io;
 use std::io::Write;
 use std::env;
 use std::fmt;
 use std::fs;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::process::ExitCode;
 use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::num::IntErrorKind;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+// Report an error with a diagnostic code, see DIAGNOSTICS for the list
+macro_rules! error {
+    ($code:literal, $($arg:tt)+) => (panic!("error[{}]: {}", $code, format!($($arg)+)))
+}
 
-// Kinds of instructions (opcodes) we support
-#[derive(Debug)]
-enum Op
-{
-    Exit,
-    Error,
-    Push,
-    GetLocal,
-    SetLocal,
-    Equal,
-    LessThan,
-    IfTrue,
-    IfNot,
-    Add,
-    Sub,
-    ReadInt,
-    Print
+// Report a warning with a diagnostic code
+macro_rules! warning {
+    ($code:literal, $($arg:tt)+) => (eprintln!("warning[{}]: {}", $code, format!($($arg)+)))
+}
+
+/// Reference information about an opcode
+struct OpInfo
+{
+    /// Name of the opcode
+    name: &'static str,
+
+    /// Kind of immediate argument the instruction takes
+    imm: &'static str,
+
+    /// Stack effect, values popped -- values pushed
+    stack: &'static str,
+
+    /// What the instruction does
+    desc: &'static str,
+}
+
+// Generates the Op enum along with a table describing each opcode, so that
+// the instruction set is defined in a single place
+macro_rules! define_ops {
+    ($($op:ident, $imm:literal, $stack:literal, $desc:literal;)*) => {
+        // Kinds of instructions (opcodes) we support
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        enum Op
+        {
+            $($op,)*
+        }
+
+        /// Information about each opcode, in the same order as the Op enum
+        const OP_TABLE: &[OpInfo] = &[
+            $(OpInfo { name: stringify!($op), imm: $imm, stack: $stack, desc: $desc },)*
+        ];
+
+        /// All opcodes, indexed by their numeric value
+        const ALL_OPS: &[Op] = &[
+            $(Op::$op,)*
+        ];
+    }
+}
+
+define_ops! {
+    Exit,         "none",         "val --",           "Exit the program with val as the result";
+    Error,        "none",         "--",               "Abort execution with a run-time error";
+    Push,         "value",        "-- val",           "Push the immediate value";
+    Pop,          "none",         "val --",           "Discard the value on top of the stack";
+    Dup,          "none",         "val -- val val",   "Duplicate the value on top of the stack";
+    GetLocal,     "local index",  "-- val",           "Push the value of a local variable";
+    SetLocal,     "local index",  "val --",           "Store a value into a local variable";
+    Equal,        "none",         "a b -- a==b",      "Compare two values for equality";
+    NotEqual,     "none",         "a b -- a!=b",      "Compare two values for inequality";
+    LessThan,     "none",         "a b -- a<b",       "Check if a is less than b";
+    LessEqual,    "none",         "a b -- a<=b",      "Check if a is less than or equal to b";
+    GreaterThan,  "none",         "a b -- a>b",       "Check if a is greater than b";
+    GreaterEqual, "none",         "a b -- a>=b",      "Check if a is greater than or equal to b";
+    IfTrue,       "jump offset",  "test --",          "Jump if test is truthy";
+    IfNot,        "jump offset",  "test --",          "Jump if test is not truthy";
+    Jump,         "jump offset",  "--",               "Jump unconditionally";
+    ForLoop,      "loop index",   "--",               "Step a for loop variable, jumping back to the body until past the limit";
+    Call,         "fun index",    "args -- result",   "Call a user-defined function";
+    CallIndirect, "num args",     "args f -- result", "Call a function reference";
+    Return,       "none",         "result -- result", "Return from a function call";
+    Gosub,        "jump offset",  "--",               "Jump to a subroutine, remembering where to return to";
+    SubReturn,    "none",         "--",               "Return from the most recent gosub";
+    Add,          "none",         "a b -- a+b",       "Add two numbers, or concatenate if either is a string";
+    Sub,          "none",         "a b -- a-b",       "Subtract two numbers";
+    Mul,          "none",         "a b -- a*b",       "Multiply two numbers";
+    Div,          "none",         "a b -- a/b",       "Divide two numbers, integers round towards zero";
+    Mod,          "none",         "a b -- a%b",       "Remainder of the division of two numbers";
+    Pow,          "none",         "a b -- a^b",       "Raise a number to a power, integer powers can't be negative";
+    Cmp,          "none",         "a b -- cmp(a,b)",  "Three-way comparison, -1 if a<b, 0 if a==b, 1 if a>b";
+    Len,          "none",         "s -- len",         "Length of an array, or of a string in characters";
+    Substr,       "none",         "s i n -- sub",     "Substring of up to n characters starting at index i";
+    Find,         "none",         "s needle -- idx",  "Index of the first occurrence of needle in s, or -1";
+    ToInt,        "none",         "a -- int",         "Convert a number or boolean to an integer, rounding towards zero";
+    ToFloat,      "none",         "a -- float",       "Convert a number to a float";
+    Neg,          "none",         "a -- -a",          "Negate a number";
+    Not,          "none",         "val -- !val",      "Logical negation, true if val is not truthy";
+    AssertEq,     "assert index", "a b --",           "Abort execution with a run-time error if a and b differ";
+    NewArray,     "num elements", "elems -- array",   "Create an array from the values on top of the stack";
+    GetElem,      "none",         "array idx -- val", "Read an array element";
+    SetElem,      "none",         "array idx val --", "Write an array element";
+    ArrayPush,    "none",         "array val -- len", "Append a value to an array, giving its new length";
+    ArrayPop,     "none",         "array -- val",     "Remove and return the last element of an array";
+    HostCall,     "host index",   "args -- result",   "Call a host operation, eg for I/O";
+    Nop,          "none",         "--",               "Do nothing, eg in a slot reserved for patching";
+}
+
+impl Op
+{
+    /// Get the reference information for this opcode
+    fn info(self) -> &'static OpInfo
+    {
+        &OP_TABLE[self as usize]
+    }
+
+    /// Get the opcode for a numeric value
+    fn from_index(idx: usize) -> Op
+    {
+        ALL_OPS[idx]
+    }
+}
+
+/// Print a reference of the instruction set
+fn print_isa()
+{
+    println!("{:<13} {:<12} {:<18} {}", "opcode", "immediate", "stack effect", "description");
+
+    for info in OP_TABLE
+    {
+        println!("{:<13} {:<12} {:<18} {}", info.name, info.imm, info.stack, info.desc);
+    }
 }
 
+/// Identity of an array, to detect arrays nested in themselves
+type ArrayPtr = *const RefCell<Vec<Value>>;
+
+/// Limit on how deeply arrays can nest when printed or compared
+const MAX_ARRAY_DEPTH: usize = 10_000;
+
 #[derive(Clone, Debug)]
 enum Value
 {
     None,        // Invalid/uninitialized
     Idx(usize),  // Index
+    Bool(bool),  // Boolean value
     IntVal(i64), // Integer value
+    Float(f64),  // Floating-point value
     Str(String), // String value
+    Array(Rc<RefCell<Vec<Value>>>), // Array, shared by reference
+    Fun(usize),  // Reference to a user-defined function
 }
 
 impl Value
@@ -65,7 +187,195 @@ impl Value
     fn unwrap_int(&self) -> i64 {
         match self {
             Value::IntVal(int_val) => *int_val,
-            _ => panic!("value is not an integer")
+            _ => error!("E0102", "value is not an integer")
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        match self {
+            Value::Float(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Get a number as a float, so integers can be mixed with floats
+    fn to_float(&self) -> f64 {
+        match self {
+            Value::IntVal(int_val) => *int_val as f64,
+            Value::Float(float_val) => *float_val,
+            _ => error!("E0102", "value is not a number")
+        }
+    }
+
+    fn unwrap_fun(&self) -> usize {
+        match self {
+            Value::Fun(fun_idx) => *fun_idx,
+            _ => error!("E0102", "value is not a function")
+        }
+    }
+
+    fn unwrap_array(&self) -> &Rc<RefCell<Vec<Value>>> {
+        match self {
+            Value::Array(elems) => elems,
+            _ => error!("E0102", "value is not an array")
+        }
+    }
+
+    fn unwrap_str(&self) -> &str {
+        match self {
+            Value::Str(str_val) => str_val,
+            _ => error!("E0102", "value is not a string")
+        }
+    }
+
+    /// Check if a value counts as true in a condition
+    /// Zero, the empty string and none are false, everything else is true
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::None => false,
+            Value::Bool(bool_val) => *bool_val,
+            Value::IntVal(int_val) => *int_val != 0,
+            Value::Float(float_val) => *float_val != 0.0,
+            Value::Str(str_val) => !str_val.is_empty(),
+            Value::Array(elems) => !elems.borrow().is_empty(),
+            Value::Fun(_) => true,
+            Value::Idx(_) => panic!("index value has no truth value"),
+        }
+    }
+
+    /// Name of the type of a value, for error messages
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "none",
+            Value::Idx(_) => "index",
+            Value::Bool(_) => "boolean",
+            Value::IntVal(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::Array(_) => "array",
+            Value::Fun(_) => "function",
+        }
+    }
+
+    /// Check if two values are equal. Integers and floats compare as numbers,
+    /// otherwise values of different types are never equal.
+    fn equals(&self, other: &Value) -> bool {
+        self.equals_in(other, &mut Vec::new())
+    }
+
+    /// Check if two values are equal, given the pairs of arrays already
+    /// being compared, which are assumed equal so that cycles terminate
+    fn equals_in(&self, other: &Value, visiting: &mut Vec<(ArrayPtr, ArrayPtr)>) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::IntVal(a), Value::IntVal(b)) => a == b,
+            (Value::IntVal(_), Value::Float(_)) |
+            (Value::Float(_), Value::IntVal(_)) |
+            (Value::Float(_), Value::Float(_)) => self.to_float() == other.to_float(),
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Fun(a), Value::Fun(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => {
+                let pair = (Rc::as_ptr(a), Rc::as_ptr(b));
+                if Rc::ptr_eq(a, b) || visiting.contains(&pair) {
+                    return true;
+                }
+
+                if visiting.len() >= MAX_ARRAY_DEPTH {
+                    error!("E0117", "arrays nested more than {} deep", MAX_ARRAY_DEPTH);
+                }
+
+                visiting.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                let result = a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.equals_in(b, visiting));
+                visiting.pop();
+                result
+            }
+            _ => false,
+        }
+    }
+
+    /// Order two values of the same type, or two numbers
+    fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::IntVal(a), Value::IntVal(b)) => a.cmp(b),
+            (Value::IntVal(_), Value::Float(_)) |
+            (Value::Float(_), Value::IntVal(_)) |
+            (Value::Float(_), Value::Float(_)) => {
+                match self.to_float().partial_cmp(&other.to_float()) {
+                    Some(ord) => ord,
+                    None => error!("E0109", "cannot compare nan")
+                }
+            }
+            (Value::Str(a), Value::Str(b)) => a.cmp(b),
+            (Value::Fun(a), Value::Fun(b)) => a.cmp(b),
+            _ => error!("E0109", "cannot compare {} with {}", self.type_name(), other.type_name())
+        }
+    }
+}
+
+/// Free nested arrays one at a time, since dropping them recursively could
+/// overflow the stack, eg after a = [a] is run a million times
+impl Drop for Value {
+    fn drop(&mut self) {
+        let mut pending = match self {
+            Value::Array(elems) => match Rc::get_mut(elems) {
+                Some(elems) => std::mem::take(elems.get_mut()),
+                None => return,
+            },
+            _ => return,
+        };
+
+        while let Some(mut val) = pending.pop() {
+            if let Value::Array(elems) = &mut val {
+                if let Some(elems) = Rc::get_mut(elems) {
+                    pending.append(elems.get_mut());
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_in(f, &mut Vec::new())
+    }
+}
+
+impl Value {
+    /// Format a value, given the arrays it is nested in. An array nested
+    /// in itself is printed as [...], to avoid looping forever.
+    fn fmt_in(&self, f: &mut fmt::Formatter, parents: &mut Vec<ArrayPtr>) -> fmt::Result {
+        match self {
+            Value::None => write!(f, "none"),
+            Value::Idx(idx) => write!(f, "#{}", idx),
+            Value::Bool(bool_val) => write!(f, "{}", bool_val),
+            Value::IntVal(int_val) => write!(f, "{}", int_val),
+            // Floats always have a decimal point, to tell them from integers
+            Value::Float(float_val) => write!(f, "{:?}", float_val),
+            Value::Str(str_val) => write!(f, "{}", str_val),
+            Value::Array(elems) => {
+                if parents.contains(&Rc::as_ptr(elems)) {
+                    return write!(f, "[...]");
+                }
+
+                if parents.len() >= MAX_ARRAY_DEPTH {
+                    error!("E0117", "arrays nested more than {} deep", MAX_ARRAY_DEPTH);
+                }
+
+                parents.push(Rc::as_ptr(elems));
+                write!(f, "[")?;
+                for (idx, elem) in elems.borrow().iter().enumerate()
+                {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    elem.fmt_in(f, parents)?;
+                }
+                parents.pop();
+                write!(f, "]")
+            }
+            Value::Fun(fun_idx) => write!(f, "<function #{}>", fun_idx),
         }
     }
 }
@@ -79,7 +389,150 @@ struct Insn
 
 impl fmt::Debug for Insn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Insn {{ op: {:10} imm: {:?} }}", format!("{:?},", self.op), self.imm)
+        write!(f, "Insn {{ op: {:13} imm: {:?} }}", format!("{},", self.op.info().name), self.imm)
+    }
+}
+
+/// Compact encoding of instructions for execution. Each instruction is a
+/// single 32-bit word, with the opcode in the low 8 bits and the index of
+/// its immediate value in the constant pool in the upper 24 bits. Since
+/// instructions have a fixed size, jump offsets are the same as for Insn.
+#[derive(Debug, Default)]
+struct Bytecode
+{
+    /// Encoded instructions
+    words: Vec<u32>,
+
+    /// Pool of immediate values, the first one being Value::None.
+    /// Equal immediates share the same entry.
+    consts: Vec<Value>,
+}
+
+/// Key identifying equal immediate values in the constant pool. Floats
+/// are keyed by their bits, so that eg 0.0 and -0.0 stay distinct.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey
+{
+    Idx(usize),
+    Bool(bool),
+    IntVal(i64),
+    Float(u64),
+    Str(String),
+    Fun(usize),
+}
+
+impl ConstKey
+{
+    /// Get the key of an immediate value, if it can be shared. Arrays are
+    /// mutable, so each one needs its own entry.
+    fn of(val: &Value) -> Option<ConstKey>
+    {
+        match val {
+            Value::Idx(idx) => Some(ConstKey::Idx(*idx)),
+            Value::Bool(bool_val) => Some(ConstKey::Bool(*bool_val)),
+            Value::IntVal(int_val) => Some(ConstKey::IntVal(*int_val)),
+            Value::Float(float_val) => Some(ConstKey::Float(float_val.to_bits())),
+            Value::Str(str_val) => Some(ConstKey::Str(str_val.clone())),
+            Value::Fun(fun_idx) => Some(ConstKey::Fun(*fun_idx)),
+            Value::None | Value::Array(_) => None,
+        }
+    }
+}
+
+/// Number of bits used to encode the opcode of an instruction
+const OPCODE_BITS: u32 = 8;
+
+impl Bytecode
+{
+    /// Encode a list of instructions
+    fn encode(insns: &[Insn]) -> Self
+    {
+        let mut code = Bytecode {
+            words: Vec::with_capacity(insns.len()),
+            consts: vec![Value::None],
+        };
+
+        // Index of each shareable value already in the pool
+        let mut const_idxs: HashMap<ConstKey, usize> = HashMap::default();
+
+        for insn in insns
+        {
+            let const_idx = if insn.imm.is_none() {
+                0
+            } else {
+                let key = ConstKey::of(&insn.imm);
+                match key.as_ref().and_then(|key| const_idxs.get(key)) {
+                    Some(const_idx) => *const_idx,
+                    None => {
+                        code.consts.push(insn.imm.clone());
+                        if let Some(key) = key {
+                            const_idxs.insert(key, code.consts.len() - 1);
+                        }
+                        code.consts.len() - 1
+                    }
+                }
+            };
+
+            if const_idx >= 1 << (32 - OPCODE_BITS) {
+                panic!("too many constants to encode");
+            }
+
+            code.words.push(((const_idx as u32) << OPCODE_BITS) | (insn.op as u32));
+        }
+
+        return code;
+    }
+
+    /// Number of instructions
+    fn len(&self) -> usize
+    {
+        self.words.len()
+    }
+
+    /// Decode the instruction at a given index into its opcode and immediate
+    fn decode(&self, idx: usize) -> (Op, &Value)
+    {
+        let word = self.words[idx];
+        let op = Op::from_index((word & ((1 << OPCODE_BITS) - 1)) as usize);
+        let imm = &self.consts[(word >> OPCODE_BITS) as usize];
+        return (op, imm);
+    }
+}
+
+/// Interned identifier, compared and hashed as an integer id
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct Symbol(usize);
+
+/// Table of interned identifier strings
+#[derive(Debug, Default)]
+struct Interner
+{
+    /// Identifier string for each symbol id
+    names: Vec<String>,
+
+    /// Mapping of identifier strings to symbols
+    symbols: HashMap<String, Symbol>,
+}
+
+impl Interner
+{
+    /// Get the symbol for an identifier, creating it if needed
+    fn intern(&mut self, name: &str) -> Symbol
+    {
+        if let Some(sym) = self.symbols.get(name) {
+            return *sym;
+        }
+
+        let sym = Symbol(self.names.len());
+        self.names.push(name.to_owned());
+        self.symbols.insert(name.to_owned(), sym);
+        return sym;
+    }
+
+    /// Get the identifier string for a symbol
+    fn name(&self, sym: Symbol) -> &str
+    {
+        &self.names[sym.0]
     }
 }
 
@@ -89,8 +542,130 @@ struct Program
     /// List of instructions
     insns: Vec<Insn>,
 
+    /// Instructions encoded for execution, once parsing is done
+    code: Bytecode,
+
+    /// Interned identifiers
+    symbols: Interner,
+
     /// Mapping of identifiers to local variable indices
-    local_idxs: HashMap<String, usize>,
+    local_idxs: HashMap<Symbol, usize>,
+
+    /// Number of local variables, including hidden temporaries
+    num_locals: usize,
+
+    /// User-defined functions
+    funs: Vec<Function>,
+
+    /// Mapping of function names to function indices
+    fun_idxs: HashMap<Symbol, usize>,
+
+    /// Are we currently parsing the body of a function
+    in_fun: bool,
+
+    /// Source information for assert_eq statements
+    assert_eqs: Vec<AssertEqInfo>,
+
+    /// Operands of ForLoop instructions
+    for_loops: Vec<ForLoopInfo>,
+
+    /// Loops enclosing the statement being parsed, innermost last
+    loops: Vec<LoopInfo>,
+
+    /// Mapping of label names to instruction indices, in the current function
+    labels: HashMap<Symbol, usize>,
+
+    /// Goto statements whose label may not be defined yet
+    gotos: Vec<PendingGoto>,
+}
+
+/// Source information about an assert_eq statement, reported on failure
+#[derive(Debug)]
+struct AssertEqInfo
+{
+    /// Source location of the statement, eg "tests.bas:12"
+    location: String,
+
+    /// Source text of the left operand
+    left_text: String,
+
+    /// Source text of the right operand
+    right_text: String,
+}
+
+/// Operands of a ForLoop instruction, which don't fit in one immediate
+#[derive(Debug)]
+struct ForLoopInfo
+{
+    /// Local holding the loop variable
+    local_idx: usize,
+
+    /// Hidden local holding the limit, evaluated once before the loop
+    limit_idx: usize,
+
+    /// Constant added to the loop variable after each iteration
+    step: i64,
+
+    /// Index of the first instruction of the loop body
+    body_idx: usize,
+}
+
+/// Loop being parsed, with the jumps to patch once its end is known
+#[derive(Debug, Default)]
+struct LoopInfo
+{
+    /// Slots reserved for break statements, jumping out of the loop
+    break_slots: Vec<usize>,
+
+    /// Slots reserved for continue statements, jumping to the next iteration
+    continue_slots: Vec<usize>,
+}
+
+/// Goto or gosub statement to be patched once all labels are known
+#[derive(Debug)]
+struct PendingGoto
+{
+    /// Jump instruction to patch in, Jump or Gosub
+    op: Op,
+
+    /// Name of the target label
+    label: Symbol,
+
+    /// Slot reserved for the jump
+    slot_idx: usize,
+
+    /// Source position of the label name, for errors
+    pos: usize,
+}
+
+/// User-defined function
+#[derive(Debug)]
+struct Function
+{
+    /// Name of the function
+    name: Symbol,
+
+    /// Index of the first instruction of the function body
+    entry_idx: usize,
+
+    /// Number of parameters, which are the first locals of the function
+    num_params: usize,
+
+    /// Names of the parameters, for named arguments
+    param_names: Vec<Symbol>,
+
+    /// Does the last parameter collect any extra arguments into an array
+    is_variadic: bool,
+
+    /// Number of local variables, including parameters
+    num_locals: usize,
+}
+
+/// Offset of a jump to a target instruction. This is relative to the
+/// instruction after the jump, since the pc gets incremented first.
+fn jump_offset(jump_idx: usize, target_idx: usize) -> i64
+{
+    (target_idx as i64) - (jump_idx as i64) - 1
 }
 
 impl Program
@@ -99,7 +674,18 @@ impl Program
     {
         Program {
             insns: Vec::default(),
+            code: Bytecode::default(),
+            symbols: Interner::default(),
             local_idxs: HashMap::default(),
+            num_locals: 0,
+            funs: Vec::default(),
+            fun_idxs: HashMap::default(),
+            in_fun: false,
+            assert_eqs: Vec::default(),
+            for_loops: Vec::default(),
+            loops: Vec::default(),
+            labels: HashMap::default(),
+            gotos: Vec::default(),
         }
     }
 
@@ -121,61 +707,223 @@ impl Program
         });
     }
 
+    /// Reserve an instruction slot to be patched later, eg with a forward
+    /// jump whose target isn't known yet. Until then, the slot is a Nop.
+    fn reserve_slot(&mut self) -> usize
+    {
+        let slot_idx = self.insns.len();
+        self.append_insn(Op::Nop);
+        return slot_idx;
+    }
+
+    /// Patch a reserved slot with a jump to a given target instruction
+    fn patch_jump(&mut self, slot_idx: usize, op: Op, target_idx: usize)
+    {
+        self.insns[slot_idx] = Insn {
+            op: op,
+            imm: Value::IntVal(jump_offset(slot_idx, target_idx))
+        };
+    }
+
+    /// Append a jump to an already known target instruction
+    fn append_jump(&mut self, op: Op, target_idx: usize)
+    {
+        let jump_idx = self.insns.len();
+        self.append_insn_imm(op, Value::IntVal(jump_offset(jump_idx, target_idx)));
+    }
+
+    /// Remove unreachable instructions starting at a given index, along
+    /// with the jumps still to be patched into them and the functions
+    /// declared there
+    fn truncate_insns(&mut self, start_idx: usize)
+    {
+        self.insns.truncate(start_idx);
+
+        for loop_info in &mut self.loops {
+            loop_info.break_slots.retain(|slot_idx| *slot_idx < start_idx);
+            loop_info.continue_slots.retain(|slot_idx| *slot_idx < start_idx);
+        }
+
+        self.gotos.retain(|goto| goto.slot_idx < start_idx);
+
+        // Functions declared in the removed code can't be called
+        let num_funs = self.funs.iter().position(|fun| fun.entry_idx >= start_idx).unwrap_or(self.funs.len());
+        self.funs.truncate(num_funs);
+        self.fun_idxs.retain(|_, fun_idx| *fun_idx < num_funs);
+
+        // Labels in the removed code now point to what comes after it
+        for label_idx in self.labels.values_mut() {
+            *label_idx = (*label_idx).min(start_idx);
+        }
+    }
+
+    /// If the instructions starting at a given index just push a constant,
+    /// remove them and return the constant
+    fn take_const(&mut self, start_idx: usize) -> Option<Value>
+    {
+        if self.insns.len() != start_idx + 1 {
+            return None;
+        }
+
+        match self.insns[start_idx].op {
+            Op::Push => Some(self.insns.pop().unwrap().imm),
+            _ => None,
+        }
+    }
+
     /// Try to find the index for local variable declaration
-    fn find_local(&self, ident: &str) -> Option<usize>
+    fn find_local(&self, ident: Symbol) -> Option<usize>
     {
-        match self.local_idxs.get(ident) {
+        match self.local_idxs.get(&ident) {
             Some(idx) => Some(*idx),
             None => None,
         }
     }
 
     /// Declare a new local variable
-    fn declare_local(&mut self, ident: &str) -> usize
+    fn declare_local(&mut self, ident: Symbol) -> usize
     {
         assert!(self.find_local(ident).is_none());
-        let local_idx = self.local_idxs.len();
-        self.local_idxs.insert(ident.to_owned(), local_idx);
+        let local_idx = self.declare_temp();
+        self.local_idxs.insert(ident, local_idx);
         return local_idx;
     }
+
+    /// Declare a hidden temporary local, not accessible by name
+    fn declare_temp(&mut self) -> usize
+    {
+        let local_idx = self.num_locals;
+        self.num_locals += 1;
+        return local_idx;
+    }
+
+    /// Patch the continue statements of the innermost loop to jump to the
+    /// current position, where the next iteration starts
+    fn patch_continues(&mut self)
+    {
+        let loop_info = self.loops.last_mut().unwrap();
+        let slot_idxs = std::mem::take(&mut loop_info.continue_slots);
+        let target_idx = self.insns.len();
+
+        for slot_idx in slot_idxs {
+            self.patch_jump(slot_idx, Op::Jump, target_idx);
+        }
+    }
+
+    /// Finish parsing the innermost loop, patching its break statements
+    /// to jump to the current position, after the loop
+    fn end_loop(&mut self)
+    {
+        let loop_info = self.loops.pop().unwrap();
+        let exit_idx = self.insns.len();
+
+        for slot_idx in loop_info.break_slots {
+            self.patch_jump(slot_idx, Op::Jump, exit_idx);
+        }
+    }
 }
 
+/// Default limit on how deeply statements and expressions can nest
+const DEFAULT_MAX_DEPTH: usize = 200;
+
 /// Stream of input characters to be parsed
 struct Input
 {
+    /// Name of the source file being parsed
+    file_name: String,
+
     /// Characters of the input string
     chars: Vec<char>,
 
     /// Current position in the input
     pos: usize,
+
+    /// Current nesting depth of statements and sub-expressions
+    depth: usize,
+
+    /// Maximum nesting depth allowed, so that pathological inputs
+    /// can't overflow the stack while parsing
+    max_depth: usize,
 }
 
 impl Input
 {
-    fn new(input_str: String) -> Self
+    fn new(input_str: String, file_name: &str) -> Self
     {
         Input {
+            file_name: file_name.to_owned(),
             chars: input_str.chars().collect(),
             pos: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
-    /// Peek at the current input character
-    fn peek_char(&self) -> char
+    /// Get the line number (starting at 1) of a given position
+    fn line_at(&self, pos: usize) -> usize
     {
-        if self.pos >= self.chars.len() {
-            return '\0';
-        }
-
-        self.chars[self.pos]
+        let num_newlines = self.chars[..pos].iter().filter(|ch| **ch == '\n').count();
+        num_newlines + 1
     }
 
-    /// Consume one character of the input
-    fn eat_char(&mut self) -> char {
+    /// Check if only whitespace was skipped since a given position on the
+    /// same line, before reaching the end of the line or of the input, or
+    /// if the next token closes the enclosing block, eg begin return end
+    fn at_stmt_end(&mut self, since_pos: usize) -> bool
+    {
         let ch = self.peek_char();
-
-        if ch != '\0' {
-            self.pos += 1;
+        if ch == '\0' || ch == '#' || self.chars[since_pos..self.pos].contains(&'\n') {
+            return true;
+        }
+
+        BLOCK_END_KEYWORDS.iter().any(|keyword| self.peek_keyword(keyword))
+    }
+
+    /// Print the source location of a given position, after a diagnostic
+    fn print_location(&self, pos: usize)
+    {
+        eprintln!("  --> {}:{}:{}", self.file_name, self.line_at(pos), self.col_at(pos));
+    }
+
+    /// Get the column number (starting at 1) of a given position
+    fn col_at(&self, pos: usize) -> usize
+    {
+        let line_start = self.chars[..pos].iter().rposition(|ch| *ch == '\n').map_or(0, |idx| idx + 1);
+        pos - line_start + 1
+    }
+
+    /// Enter a nested statement or sub-expression
+    fn enter_nested(&mut self)
+    {
+        self.depth += 1;
+
+        if self.depth > self.max_depth {
+            error!("E0007", "maximum nesting depth of {} exceeded\n", self.max_depth);
+        }
+    }
+
+    /// Leave a nested statement or sub-expression
+    fn leave_nested(&mut self)
+    {
+        self.depth -= 1;
+    }
+
+    /// Peek at the current input character
+    fn peek_char(&self) -> char
+    {
+        if self.pos >= self.chars.len() {
+            return '\0';
+        }
+
+        self.chars[self.pos]
+    }
+
+    /// Consume one character of the input
+    fn eat_char(&mut self) -> char {
+        let ch = self.peek_char();
+
+        if ch != '\0' {
+            self.pos += 1;
         }
 
         return ch
@@ -249,11 +997,37 @@ impl Input
         return false;
     }
 
+    /// Check if the input starts with a given keyword, which must not be
+    /// immediately followed by more identifier characters
+    fn match_keyword(&mut self, keyword: &str) -> bool
+    {
+        self.eat_ws();
+
+        let num_chars = keyword.chars().count();
+        let next_ch = self.chars.get(self.pos + num_chars).copied().unwrap_or('\0');
+
+        // Make sure we match the whole word, eg "_" but not "_x"
+        if next_ch.is_alphanumeric() || next_ch == '_' {
+            return false;
+        }
+
+        return self.match_token(keyword);
+    }
+
+    /// Check if a keyword comes next, without consuming it
+    fn peek_keyword(&mut self, keyword: &str) -> bool
+    {
+        let start_pos = self.pos;
+        let found = self.match_keyword(keyword);
+        self.pos = start_pos;
+        found
+    }
+
     /// Fail to parse if a given token is not there
     fn expect_token(&mut self, token: &str)
     {
         if !self.match_token(token) {
-            panic!("expected token \"{}\"", token);
+            error!("E0001", "expected token \"{}\"", token);
         }
     }
 
@@ -262,6 +1036,11 @@ impl Input
     {
         let mut ident_str = String::from("");
 
+        // Identifiers can't start with a digit
+        if self.peek_char().is_digit(10) {
+            error!("E0002", "expected identifier\n");
+        }
+
         loop
         {
             let ch = self.peek_char();
@@ -278,7 +1057,7 @@ impl Input
         }
 
         if ident_str.len() == 0 {
-            panic!("expected identifier\n");
+            error!("E0002", "expected identifier\n");
         }
 
         return ident_str;
@@ -307,388 +1086,3192 @@ impl Input
 
         return num;
     }
+
+    /// Parse a string literal, after the opening double quote
+    fn parse_string(&mut self) -> String
+    {
+        let start_pos = self.pos - 1;
+        let mut str_val = String::new();
+
+        loop
+        {
+            let ch = self.eat_char();
+
+            match ch
+            {
+                '"' => break,
+
+                '\0' | '\n' => {
+                    self.pos = start_pos;
+                    error!("E0013", "unterminated string literal\n");
+                }
+
+                // Escape sequences
+                '\\' => {
+                    let esc_ch = self.eat_char();
+
+                    match esc_ch
+                    {
+                        'n' => str_val.push('\n'),
+                        't' => str_val.push('\t'),
+                        '"' => str_val.push('"'),
+                        '\\' => str_val.push('\\'),
+                        _ => error!("E0013", "invalid escape sequence \"\\{}\"\n", esc_ch),
+                    }
+                }
+
+                _ => str_val.push(ch),
+            }
+        }
+
+        return str_val;
+    }
 }
 
-/// Parse an atomic expression
-fn parse_atom(input: &mut Input, prog: &mut Program)
+/// Keywords that can start a statement
+const STMT_KEYWORDS: [&str; 18] = [
+    "let", "if", "select", "for", "repeat", "do", "break", "continue", "label", "goto", "gosub",
+    "fun", "begin", "print", "trace", "assert", "assert_eq", "return"
+];
+
+/// Keywords that close a block, and so end the statement before them
+const BLOCK_END_KEYWORDS: [&str; 5] = ["end", "else", "elseif", "case", "until"];
+
+/// Compute the edit distance between two strings, counting insertions,
+/// deletions, substitutions and swaps of adjacent characters
+fn edit_distance(a: &str, b: &str) -> usize
 {
-    // Parenthesized sub-expression
-    if input.match_token("(") {
-        parse_expr(input, prog);
-        input.expect_token(")");
-        return;
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // dist[i][j] is the distance between a[..i] and b[..j]
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for i in 0..=a.len() {
+        dist[i][0] = i;
     }
 
-    // Read an integer from the console
-    if input.match_token("read_int") {
-        prog.append_insn(Op::ReadInt);
-        return;
+    for j in 0..=b.len() {
+        dist[0][j] = j;
     }
 
-    let ch = input.peek_char();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
 
-    // Integer constant
-    if ch.is_digit(10) {
-        let num = input.parse_int();
-        prog.append_insn_imm(Op::Push, Value::IntVal(num));
-        return;
+            dist[i][j] = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+
+            // Transposition of two adjacent characters
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                dist[i][j] = dist[i][j].min(dist[i - 2][j - 2] + 1);
+            }
+        }
     }
 
-    // Reference to a variable
-    if ch.is_alphabetic() || ch == '_' {
-        // Parse the variable name
-        let ident_str = input.parse_ident();
+    return dist[a.len()][b.len()];
+}
 
-        // Try to find the declaration
-        let local_idx = prog.find_local(&ident_str);
+/// Find the candidate closest to a misspelled word, if any is close enough
+fn closest_match<'a, I>(word: &str, candidates: I) -> Option<&'a str>
+where I: Iterator<Item = &'a str>
+{
+    // Allow roughly one typo for every three characters
+    let max_dist = std::cmp::max(1, word.chars().count() / 3);
+
+    let mut best: Option<(usize, &str)> = None;
+
+    for candidate in candidates
+    {
+        let dist = edit_distance(word, candidate);
 
-        if local_idx.is_none() {
-            panic!("reference to undeclared variable \"{}\"\n", ident_str);
+        if dist > max_dist {
+            continue;
         }
 
-        prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx.unwrap()));
-        return;
+        // Break ties by name, since candidates often come from a HashMap
+        if best.is_none() || (dist, candidate) < best.unwrap() {
+            best = Some((dist, candidate));
+        }
     }
 
-    panic!("invalid atomic expression");
+    return best.map(|(_, candidate)| candidate);
 }
 
-/// Parse an expression
-fn parse_expr(input: &mut Input, prog: &mut Program)
+/// Format a "did you mean" hint to append to an error message
+fn did_you_mean(suggestion: Option<&str>) -> String
 {
-    // Parse a first expression
-    parse_atom(input, prog);
+    match suggestion {
+        Some(s) => format!(", did you mean \"{}\"?", s),
+        None => String::new(),
+    }
+}
 
-    if input.match_token("+") {
-        // Parse the RHS expression
-        parse_atom(input, prog);
+/// Parse an atomic expression followed by any number of indexing
+/// operations, eg a[i][j]
+fn parse_postfix(input: &mut Input, prog: &mut Program)
+{
+    parse_atom(input, prog);
 
-        // Add the result
-        prog.append_insn(Op::Add);
-        return;
+    while input.match_token("[") {
+        input.enter_nested();
+        parse_expr(input, prog);
+        input.expect_token("]");
+        input.leave_nested();
+        prog.append_insn(Op::GetElem);
     }
+}
 
-    if input.match_token("-") {
-        // Parse the RHS expression
-        parse_atom(input, prog);
-
-        // Subtract the result
-        prog.append_insn(Op::Sub);
+/// Parse an atomic expression
+fn parse_atom(input: &mut Input, prog: &mut Program)
+{
+    // Parenthesized sub-expression
+    if input.match_token("(") {
+        input.enter_nested();
+        parse_expr(input, prog);
+        input.expect_token(")");
+        input.leave_nested();
         return;
     }
 
-    if input.match_token("==") {
-        // Parse the RHS expression
-        parse_atom(input, prog);
+    // Unary minus, eg -2^2 is -(2^2)
+    if input.match_token("-") {
+        input.enter_nested();
+        let start_idx = prog.insns.len();
+        parse_expr_prec(input, prog, POW_PREC);
+        input.leave_nested();
+
+        // Negative constants are folded, eg so they can be used as a for loop step
+        match prog.take_const(start_idx) {
+            Some(Value::IntVal(int_val)) => {
+                prog.append_insn_imm(Op::Push, Value::IntVal(int_val.wrapping_neg()));
+            }
+            Some(Value::Float(float_val)) => {
+                prog.append_insn_imm(Op::Push, Value::Float(-float_val));
+            }
+            Some(val) => {
+                prog.append_insn_imm(Op::Push, val);
+                prog.append_insn(Op::Neg);
+            }
+            None => prog.append_insn(Op::Neg),
+        }
 
-        // Compare the arguments
-        prog.append_insn(Op::Equal);
         return;
     }
 
-    if input.match_token("<") {
-        // Parse the RHS expression
-        parse_atom(input, prog);
-
-        // Compare the arguments
-        prog.append_insn(Op::LessThan);
+    // Logical negation
+    if input.match_keyword("not") {
+        input.enter_nested();
+        parse_postfix(input, prog);
+        input.leave_nested();
+        prog.append_insn(Op::Not);
         return;
     }
-}
 
-/// Parse a statement
-fn parse_stmt(input: &mut Input, prog: &mut Program)
-{
-    // Consume whitespace
-    input.eat_ws();
+    // Reference to a user-defined function, eg @double
+    if input.match_token("@") {
+        let fun_name = input.parse_ident();
+        let ident = prog.symbols.intern(&fun_name);
+
+        let fun_idx = match prog.fun_idxs.get(&ident) {
+            Some(fun_idx) => *fun_idx,
+            None => {
+                let suggestion = closest_match(
+                    &fun_name,
+                    prog.fun_idxs.keys().map(|sym| prog.symbols.name(*sym))
+                );
+
+                error!(
+                    "E0009",
+                    "reference to undeclared function \"{}\"{}\n",
+                    fun_name,
+                    did_you_mean(suggestion)
+                );
+            }
+        };
 
-    // Single-line comments
-    if input.match_token("#") {
-        input.eat_comment();
+        prog.append_insn_imm(Op::Push, Value::Fun(fun_idx));
         return;
     }
 
-    // Local variable declaration
-    if input.match_token("let") {
-        // Parse the variable name
-        let ident_str = input.parse_ident();
-
-        input.expect_token("=");
-
-        // Parse the expression we are assigning
-        parse_expr(input, prog);
+    // Array literal, eg [1, 2, 3]
+    if input.match_token("[") {
+        input.enter_nested();
+        let mut num_elems = 0;
+        if !input.match_token("]") {
+            loop
+            {
+                parse_expr(input, prog);
+                num_elems += 1;
 
-        // Make sure this isn't a redeclaration
-        let local_idx = prog.find_local(&ident_str);
+                if input.match_token("]") {
+                    break;
+                }
 
-        if local_idx.is_some() {
-            panic!("local variable \"{}\" already declared\n", ident_str);
+                input.expect_token(",");
+            }
         }
+        input.leave_nested();
 
-        // Create a new local variable
-        let local_idx = prog.declare_local(&ident_str);
-
-        // Set the local to the expression's value
-        prog.append_insn_imm(Op::SetLocal, Value::Idx(local_idx));
+        prog.append_insn_imm(Op::NewArray, Value::Idx(num_elems));
+        return;
+    }
 
+    // Boolean constants
+    if input.match_keyword("true") {
+        prog.append_insn_imm(Op::Push, Value::Bool(true));
         return;
     }
 
-    if input.match_token("if") {
-        // Parse the test expression
-        parse_expr(input, prog);
+    if input.match_keyword("false") {
+        prog.append_insn_imm(Op::Push, Value::Bool(false));
+        return;
+    }
 
-        input.expect_token("then");
+    // Line number of the current source position
+    input.eat_ws();
+    let atom_pos = input.pos;
+    if input.match_keyword("__line__") {
+        let line_no = input.line_at(atom_pos) as i64;
+        prog.append_insn_imm(Op::Push, Value::IntVal(line_no));
+        return;
+    }
 
-        // If the result is false, jump past the if clause
-        let ifnot_insn_idx = prog.insns.len();
-        prog.append_insn(Op::IfNot);
+    // Name of the current source file
+    if input.match_keyword("__file__") {
+        let file_name = input.file_name.clone();
+        prog.append_insn_imm(Op::Push, Value::Str(file_name));
+        return;
+    }
 
-        // Parse the body of the if statement
-        parse_stmt(input, prog);
+    // Read an integer from the console
+    if input.match_token("read_int") {
+        prog.append_insn_imm(Op::HostCall, Value::Idx(host_fn_idx("read_int")));
+        return;
+    }
 
-        // If the condition is false, we jump after the body of the if
-        let jumpto_idx = prog.insns.len();
-        let jump_offset = (jumpto_idx as i64) - (ifnot_insn_idx as i64) - 1;
-        prog.insns[ifnot_insn_idx].imm = Value::IntVal(jump_offset);
+    let ch = input.peek_char();
 
+    // String constant
+    if ch == '"' {
+        input.eat_char();
+        let str_val = input.parse_string();
+        prog.append_insn_imm(Op::Push, Value::Str(str_val));
         return;
     }
 
-    // Sequencing of statements
-    if input.match_token("begin") {
-        loop
-        {
-            if input.match_token("end") {
-                break;
-            }
+    // Integer or floating-point constant
+    if ch.is_digit(10) {
+        let start_pos = input.pos;
+        let num = input.parse_int();
 
-            parse_stmt(input, prog);
+        // A decimal point followed by digits makes this a float
+        let mut is_float = false;
+        let next_ch = input.chars.get(input.pos + 1).copied().unwrap_or('\0');
+        if input.peek_char() == '.' && next_ch.is_digit(10) {
+            input.eat_char();
+            input.parse_int();
+            is_float = true;
         }
 
-        return;
-    }
+        // So does an exponent, eg 1e-7, which is how large and small
+        // floats get printed
+        let exp_len = match input.chars.get(input.pos + 1) {
+            Some('+') | Some('-') => 2,
+            _ => 1,
+        };
+        let next_ch = input.chars.get(input.pos + exp_len).copied().unwrap_or('\0');
+        if (input.peek_char() == 'e' || input.peek_char() == 'E') && next_ch.is_digit(10) {
+            input.pos += exp_len;
+            input.parse_int();
+            is_float = true;
+        }
 
-    // Print to stdout
-    if input.match_token("print") {
-        parse_expr(input, prog);
-        prog.append_insn(Op::Print);
+        if is_float {
+            let num_str: String = input.chars[start_pos..input.pos].iter().collect();
+            prog.append_insn_imm(Op::Push, Value::Float(num_str.parse().unwrap()));
+            return;
+        }
+
+        prog.append_insn_imm(Op::Push, Value::IntVal(num));
         return;
     }
 
-    // Assert that an expression evaluates to true
-    if input.match_token("assert") {
-        // Parse the condition
-        parse_expr(input, prog);
-
-        // If the result is true, jump over the error instruction
-        prog.append_insn_imm(Op::IfTrue, Value::IntVal(1));
+    // Reference to a variable or function call
+    if ch.is_alphabetic() || ch == '_' {
+        // Parse the variable name
+        let ident_str = input.parse_ident();
 
-        // Exit with an error
-        prog.append_insn(Op::Error);
+        if input.match_token("(") {
+            parse_call(input, prog, &ident_str);
+            return;
+        }
 
+        let local_idx = expect_local(input, prog, &ident_str, atom_pos);
+        prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx));
         return;
     }
 
-    // TODO: report more info about current position and next token
-    panic!("invalid statement");
+    error!("E0004", "invalid atomic expression");
 }
 
-/// Parse a source file into a sequence of instructions
-fn parse_file(file_name: &str) -> Program
+/// Find the index of a declared local variable, or fail to parse,
+/// reporting the error at the given position of the variable name
+fn expect_local(input: &mut Input, prog: &mut Program, ident_str: &str, ident_pos: usize) -> usize
 {
-    let input_str = fs::read_to_string(file_name)
-        .expect("couldn't read input source file");
+    let ident = prog.symbols.intern(ident_str);
 
-    // Input to be parsed
-    let mut input = Input::new(input_str);
+    // Try to find the declaration
+    if let Some(local_idx) = prog.find_local(ident) {
+        return local_idx;
+    }
 
-    // Program being compiled
-    let mut program: Program = Program::new();
+    let suggestion = closest_match(
+        ident_str,
+        prog.local_idxs.keys().map(|sym| prog.symbols.name(*sym))
+    );
+
+    input.pos = ident_pos;
+    error!(
+        "E0003",
+        "reference to undeclared variable \"{}\"{}\n",
+        ident_str,
+        did_you_mean(suggestion)
+    );
+}
 
-    // Until we reach the end of the input
-    loop
-    {
-        // End of input
-        if input.peek_char() == '\0' {
-            break;
+/// Parse a function call, after the function name and opening parenthesis
+fn parse_call(input: &mut Input, prog: &mut Program, fun_name: &str)
+{
+    let ident = prog.symbols.intern(fun_name);
+
+    // Call through a function reference stored in a local variable
+    if let Some(local_idx) = prog.find_local(ident) {
+        let args = parse_args(input, prog);
+        let num_args = positional_args(input, &args);
+        prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx));
+        prog.append_insn_imm(Op::CallIndirect, Value::Idx(num_args));
+        return;
+    }
+
+    // User-defined functions take precedence over builtins with the same name
+    let fun_idx = prog.fun_idxs.get(&ident).copied();
+    let builtin = BUILTIN_FNS.iter().find(|(name, _, _)| *name == fun_name);
+
+    let num_params = match (fun_idx, builtin) {
+        (Some(fun_idx), _) => prog.funs[fun_idx].num_params,
+        (None, Some((_, arity, _))) => *arity,
+        (None, None) => {
+            let fun_names = prog.fun_idxs.keys().map(|sym| prog.symbols.name(*sym));
+            let builtin_names = BUILTIN_FNS.iter().map(|(name, _, _)| *name);
+            let suggestion = closest_match(fun_name, fun_names.chain(builtin_names));
+
+            error!(
+                "E0009",
+                "call to undeclared function \"{}\"{}\n",
+                fun_name,
+                did_you_mean(suggestion)
+            );
         }
+    };
+
+    let args_pos = input.pos;
+    let args = parse_args(input, prog);
+    let num_args = args.len();
+
+    // Variadic functions can take any number of extra arguments
+    let is_variadic = fun_idx.is_some_and(|fun_idx| prog.funs[fun_idx].is_variadic);
+
+    if is_variadic && num_args < num_params - 1 {
+        input.pos = args_pos;
+        error!(
+            "E0012",
+            "function \"{}\" takes at least {} arguments but {} were given\n",
+            fun_name,
+            num_params - 1,
+            num_args
+        );
+    }
 
-        parse_stmt(&mut input, &mut program);
+    if !is_variadic && num_args != num_params {
+        input.pos = args_pos;
+        error!(
+            "E0012",
+            "function \"{}\" takes {} arguments but {} were given\n",
+            fun_name,
+            num_params,
+            num_args
+        );
     }
 
-    return program;
-}
+    match (fun_idx, builtin) {
+        (Some(fun_idx), _) => {
+            order_named_args(input, prog, fun_idx, &args);
 
-// A quick immitation of env_logger crate that enables logging with RUST_LOG=debug
-macro_rules! debug {
-    ($($arg:tt)+) => (if env::var("RUST_LOG").is_ok() { println!($($arg)+) })
+            // Collect the extra arguments into an array for the last parameter
+            if is_variadic {
+                prog.append_insn_imm(Op::NewArray, Value::Idx(num_args - (num_params - 1)));
+            }
+
+            prog.append_insn_imm(Op::Call, Value::Idx(fun_idx));
+        }
+        (None, Some((_, _, op))) => {
+            positional_args(input, &args);
+            prog.append_insn(*op);
+        }
+        (None, None) => unreachable!(),
+    }
 }
 
-/// Virtual machine / interpreter
-struct VM
+/// Argument of a function call
+struct CallArg
 {
-    /// Local variables
-    locals: Vec<Value>,
-
-    /// Stack of temporary values
-    stack: Vec<Value>,
+    /// Parameter name, for named arguments, eg x in f(x: 1)
+    name: Option<String>,
 
-    /// Program counter
-    pc: usize,
+    /// Source position of the argument, for error messages
+    pos: usize,
 }
 
-impl VM
+/// Check that a call to a function whose parameters aren't known at
+/// compile time only has positional arguments, and count them
+fn positional_args(input: &mut Input, args: &[CallArg]) -> usize
 {
-    fn new() -> Self {
-        VM {
-            locals: Vec::default(),
-            stack: Vec::default(),
-            pc: 0,
+    for arg in args
+    {
+        if arg.name.is_some() {
+            input.pos = arg.pos;
+            error!("E0014", "named arguments can only be passed to user-defined functions\n");
         }
     }
 
-    fn push(&mut self, val: Value) {
-        self.stack.push(val);
-    }
+    return args.len();
+}
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
-    }
+/// Match named arguments with the parameters of a function, and reorder
+/// the argument values on the stack to be in parameter order if needed
+fn order_named_args(input: &mut Input, prog: &mut Program, fun_idx: usize, args: &[CallArg])
+{
+    let fun = &prog.funs[fun_idx];
 
-    // Evaluate/run a program
-    fn eval(&mut self, prog: Program)
-    {
-        let num_locals = prog.local_idxs.len();
+    if fun.is_variadic {
+        if let Some(arg) = args.iter().find(|arg| arg.name.is_some()) {
+            input.pos = arg.pos;
+            error!("E0014", "variadic functions can't take named arguments\n");
+        }
+        return;
+    }
 
-        self.locals.resize(num_locals, Value::None);
+    // Parameter index for each argument, in source order
+    let mut param_idxs: Vec<usize> = Vec::new();
 
-        self.pc = 0;
+    for (arg_idx, arg) in args.iter().enumerate()
+    {
+        let param_idx = match &arg.name {
+            None => {
+                if args[..arg_idx].iter().any(|prev_arg| prev_arg.name.is_some()) {
+                    input.pos = arg.pos;
+                    error!("E0014", "positional arguments must come before named arguments\n");
+                }
+                arg_idx
+            }
+            Some(name) => {
+                let param_names = fun.param_names.iter().map(|sym| prog.symbols.name(*sym));
+                match param_names.clone().position(|param_name| param_name == name) {
+                    Some(param_idx) => param_idx,
+                    None => {
+                        input.pos = arg.pos;
+                        error!(
+                            "E0014",
+                            "function \"{}\" has no parameter \"{}\"{}\n",
+                            prog.symbols.name(fun.name),
+                            name,
+                            did_you_mean(closest_match(name, param_names))
+                        );
+                    }
+                }
+            }
+        };
+
+        if param_idxs.contains(&param_idx) {
+            input.pos = arg.pos;
+            error!(
+                "E0014",
+                "parameter \"{}\" is given more than one argument\n",
+                prog.symbols.name(fun.param_names[param_idx])
+            );
+        }
+
+        param_idxs.push(param_idx);
+    }
+
+    // Nothing to do if the arguments are already in parameter order
+    if param_idxs.iter().enumerate().all(|(arg_idx, param_idx)| arg_idx == *param_idx) {
+        return;
+    }
+
+    // Move the argument values into temporaries, and push them back in
+    // parameter order. The last argument is on top of the stack.
+    let temp_idxs: Vec<usize> = args.iter().map(|_| prog.declare_temp()).collect();
+
+    for param_idx in param_idxs.iter().rev()
+    {
+        prog.append_insn_imm(Op::SetLocal, Value::Idx(temp_idxs[*param_idx]));
+    }
+
+    for temp_idx in temp_idxs
+    {
+        prog.append_insn_imm(Op::GetLocal, Value::Idx(temp_idx));
+    }
+}
+
+/// Parse the arguments of a call up to the closing parenthesis, which get
+/// pushed on the stack in order
+fn parse_args(input: &mut Input, prog: &mut Program) -> Vec<CallArg>
+{
+    let mut args = Vec::new();
+
+    if !input.match_token(")") {
+        input.enter_nested();
+        loop
+        {
+            // Arguments can be named after a parameter, eg x: 1
+            input.eat_ws();
+            let arg_pos = input.pos;
+            let mut name = None;
+
+            let ch = input.peek_char();
+            if ch.is_alphabetic() || ch == '_' {
+                let ident_str = input.parse_ident();
+
+                if input.match_token(":") {
+                    name = Some(ident_str);
+                } else {
+                    input.pos = arg_pos;
+                }
+            }
+
+            parse_expr(input, prog);
+            args.push(CallArg { name: name, pos: arg_pos });
+
+            if input.match_token(")") {
+                break;
+            }
+
+            input.expect_token(",");
+        }
+        input.leave_nested();
+    }
+
+    return args;
+}
+
+/// Builtin functions, with their number of arguments and the opcode
+/// that implements them
+const BUILTIN_FNS: [(&str, usize, Op); 8] = [
+    ("cmp", 2, Op::Cmp),
+    ("len", 1, Op::Len),
+    ("push", 2, Op::ArrayPush),
+    ("pop", 1, Op::ArrayPop),
+    ("substr", 3, Op::Substr),
+    ("find", 2, Op::Find),
+    ("int", 1, Op::ToInt),
+    ("float", 1, Op::ToFloat),
+];
+
+/// Precedence level of ^, which binds more tightly than unary minus
+const POW_PREC: usize = 6;
+
+/// Binary operators, along with their precedence level and opcode.
+/// Operators with a higher precedence bind more tightly, and longer
+/// tokens come first so that eg "<=" doesn't get parsed as "<".
+/// The logical operators short-circuit using a conditional jump.
+const BIN_OPS: [(&str, usize, Op); 15] = [
+    ("or", 1, Op::IfTrue),
+    ("and", 2, Op::IfNot),
+    ("==", 3, Op::Equal),
+    ("!=", 3, Op::NotEqual),
+    ("<>", 3, Op::NotEqual),
+    ("<=", 3, Op::LessEqual),
+    ("<", 3, Op::LessThan),
+    (">=", 3, Op::GreaterEqual),
+    (">", 3, Op::GreaterThan),
+    ("+", 4, Op::Add),
+    ("-", 4, Op::Sub),
+    ("*", 5, Op::Mul),
+    ("/", 5, Op::Div),
+    ("%", 5, Op::Mod),
+    ("^", POW_PREC, Op::Pow),
+];
+
+/// Try to match a binary operator, returning its precedence and opcode
+fn match_bin_op(input: &mut Input) -> Option<(usize, Op)>
+{
+    for (token, prec, op) in BIN_OPS
+    {
+        let matched = if token.starts_with(char::is_alphabetic) {
+            input.match_keyword(token)
+        } else {
+            input.match_token(token)
+        };
+
+        if matched {
+            return Some((prec, op));
+        }
+    }
+
+    return None;
+}
+
+/// Parse an expression, and return its source text
+fn parse_expr_text(input: &mut Input, prog: &mut Program) -> String
+{
+    let start_pos = input.pos;
+    parse_expr(input, prog);
+    let expr_text: String = input.chars[start_pos..input.pos].iter().collect();
+    return expr_text.trim().to_owned();
+}
+
+/// Parse an expression
+fn parse_expr(input: &mut Input, prog: &mut Program)
+{
+    parse_expr_prec(input, prog, 0);
+}
+
+/// Parse an expression made of operators with at least a given precedence,
+/// using precedence climbing
+fn parse_expr_prec(input: &mut Input, prog: &mut Program, min_prec: usize)
+{
+    // Parse a first expression
+    parse_postfix(input, prog);
+
+    loop
+    {
+        let op_pos = input.pos;
+
+        let (prec, op) = match match_bin_op(input) {
+            Some(bin_op) => bin_op,
+            None => break,
+        };
+
+        // This operator binds less tightly, leave it to the caller
+        if prec < min_prec {
+            input.pos = op_pos;
+            break;
+        }
+
+        // The RHS only takes operators binding more tightly, which makes
+        // operators left-associative, except ^ which is right-associative
+        let rhs_prec = if op == Op::Pow { prec } else { prec + 1 };
+
+        input.enter_nested();
+        if op == Op::IfTrue || op == Op::IfNot {
+            // If the LHS determines the result, keep it and skip the RHS
+            prog.append_insn(Op::Dup);
+            let jump_slot = prog.reserve_slot();
+
+            // Otherwise, the result is the value of the RHS
+            prog.append_insn(Op::Pop);
+            parse_expr_prec(input, prog, rhs_prec);
+
+            prog.patch_jump(jump_slot, op, prog.insns.len());
+        } else {
+            parse_expr_prec(input, prog, rhs_prec);
+            prog.append_insn(op);
+        }
+        input.leave_nested();
+    }
+}
+
+/// Patch the pending goto and gosub statements to jump to their label, once the
+/// whole function or top level has been parsed
+fn resolve_gotos(input: &mut Input, prog: &mut Program)
+{
+    for goto in std::mem::take(&mut prog.gotos)
+    {
+        match prog.labels.get(&goto.label) {
+            Some(target_idx) => prog.patch_jump(goto.slot_idx, goto.op, *target_idx),
+            None => {
+                let label_str = prog.symbols.name(goto.label);
+                let suggestion = closest_match(
+                    label_str,
+                    prog.labels.keys().map(|sym| prog.symbols.name(*sym))
+                );
+
+                input.pos = goto.pos;
+                error!("E0016", "undefined label \"{}\"{}\n", label_str, did_you_mean(suggestion));
+            }
+        }
+    }
+}
+
+/// Parse a function declaration, after the fun keyword
+fn parse_fun(input: &mut Input, prog: &mut Program)
+{
+    if prog.in_fun {
+        error!("E0010", "functions cannot be declared inside other functions\n");
+    }
+
+    let ident_str = input.parse_ident();
+    let ident = prog.symbols.intern(&ident_str);
+
+    if prog.fun_idxs.contains_key(&ident) {
+        error!("E0011", "function \"{}\" already declared\n", ident_str);
+    }
+
+    // Functions have their own local variables, separate from the top level
+    let outer_local_idxs = std::mem::take(&mut prog.local_idxs);
+    let outer_num_locals = prog.num_locals;
+    prog.num_locals = 0;
+
+    // Loops around the declaration can't be exited from inside the body
+    let outer_loops = std::mem::take(&mut prog.loops);
+
+    // Labels are local to the function, goto can't jump out of it
+    let outer_labels = std::mem::take(&mut prog.labels);
+    let outer_gotos = std::mem::take(&mut prog.gotos);
+
+    // Parse the parameter names, which are the first locals
+    let mut param_names = Vec::new();
+    let mut is_variadic = false;
+    input.expect_token("(");
+    if !input.match_token(")") {
+        loop
+        {
+            let param_str = input.parse_ident();
+            let param = prog.symbols.intern(&param_str);
+
+            if prog.find_local(param).is_some() {
+                error!("E0005", "parameter \"{}\" already declared\n", param_str);
+            }
+
+            prog.declare_local(param);
+            param_names.push(param);
+
+            // The last parameter can take extra arguments, eg args...
+            if input.match_token("...") {
+                is_variadic = true;
+                input.expect_token(")");
+                break;
+            }
+
+            if input.match_token(")") {
+                break;
+            }
+
+            input.expect_token(",");
+        }
+    }
+
+    // The function body is only run when called, so jump over it
+    let jump_slot = prog.reserve_slot();
+
+    // Declare the function before parsing its body, so it can recurse
+    let fun_idx = prog.funs.len();
+    prog.funs.push(Function {
+        name: ident,
+        entry_idx: prog.insns.len(),
+        num_params: prog.num_locals,
+        param_names: param_names,
+        is_variadic: is_variadic,
+        num_locals: 0,
+    });
+    prog.fun_idxs.insert(ident, fun_idx);
+
+    // Parse the body of the function
+    prog.in_fun = true;
+    input.enter_nested();
+    loop
+    {
+        if input.match_keyword("end") {
+            break;
+        }
+
+        parse_stmt(input, prog);
+    }
+    input.leave_nested();
+    prog.in_fun = false;
+    resolve_gotos(input, prog);
+
+    // Go back to the top-level locals
+    prog.funs[fun_idx].num_locals = prog.num_locals;
+    prog.local_idxs = outer_local_idxs;
+    prog.num_locals = outer_num_locals;
+    prog.loops = outer_loops;
+    prog.labels = outer_labels;
+    prog.gotos = outer_gotos;
+
+    // Functions implicitly return 0 when reaching the end of their body
+    prog.append_insn_imm(Op::Push, Value::IntVal(0));
+    prog.append_insn(Op::Return);
+
+    // Patch the jump over the function body
+    prog.patch_jump(jump_slot, Op::Jump, prog.insns.len());
+}
+
+/// Parse the rest of an if statement, after the if or elseif keyword
+fn parse_if(input: &mut Input, prog: &mut Program)
+{
+    // Parse the test expression
+    input.eat_ws();
+    let test_pos = input.pos;
+    let test_insn_idx = prog.insns.len();
+    parse_expr(input, prog);
+
+    input.expect_token("then");
+
+    // If the condition is a constant, only one branch can ever run
+    if let Some(test_val) = prog.take_const(test_insn_idx) {
+        let is_true = test_val.is_truthy();
+
+        // Drop the body if it's unreachable
+        let body_insn_idx = prog.insns.len();
+        parse_nested_stmt(input, prog);
+        if !is_true {
+            warning!("W0001", "if condition is always false, body is unreachable");
+            input.print_location(test_pos);
+            prog.truncate_insns(body_insn_idx);
+        }
+
+        // Drop the else clause if it's unreachable
+        input.eat_ws();
+        let else_pos = input.pos;
+        let is_elseif = input.match_keyword("elseif");
+        if is_elseif || input.match_keyword("else") {
+            let else_insn_idx = prog.insns.len();
+            parse_else(input, prog, is_elseif);
+            if is_true {
+                warning!("W0001", "if condition is always true, else is unreachable");
+                input.print_location(else_pos);
+                prog.truncate_insns(else_insn_idx);
+            }
+        }
+
+        return;
+    }
+
+    // If the result is false, jump past the if clause
+    let ifnot_slot = prog.reserve_slot();
+
+    // Parse the body of the if statement
+    parse_nested_stmt(input, prog);
+
+    let is_elseif = input.match_keyword("elseif");
+    if is_elseif || input.match_keyword("else") {
+        // At the end of the if body, jump over the else clause
+        let jump_slot = prog.reserve_slot();
+
+        // If the condition is false, we jump to the else clause
+        prog.patch_jump(ifnot_slot, Op::IfNot, prog.insns.len());
+
+        parse_else(input, prog, is_elseif);
+
+        // All the branches of an elseif chain exit to the same point
+        prog.patch_jump(jump_slot, Op::Jump, prog.insns.len());
+
+        return;
+    }
+
+    // If the condition is false, we jump after the body of the if
+    prog.patch_jump(ifnot_slot, Op::IfNot, prog.insns.len());
+}
+
+/// Parse the body of an else clause, or the rest of an elseif chain
+fn parse_else(input: &mut Input, prog: &mut Program, is_elseif: bool)
+{
+    if is_elseif {
+        input.enter_nested();
+        parse_if(input, prog);
+        input.leave_nested();
+    } else {
+        parse_nested_stmt(input, prog);
+    }
+}
+
+/// Parse a statement nested inside another, eg the body of an if
+fn parse_nested_stmt(input: &mut Input, prog: &mut Program)
+{
+    input.enter_nested();
+    parse_stmt(input, prog);
+    input.leave_nested();
+}
+
+/// Parse a select statement, after the select keyword, eg
+/// select x case 1, 2 ... case 3 ... case else ... end
+fn parse_select(input: &mut Input, prog: &mut Program)
+{
+    // The value is evaluated once, into a hidden local
+    let value_idx = prog.declare_temp();
+    parse_expr(input, prog);
+    prog.append_insn_imm(Op::SetLocal, Value::Idx(value_idx));
+
+    // Jumps to the end of the select, after each case body
+    let mut end_slots = Vec::new();
+
+    input.enter_nested();
+    loop
+    {
+        if input.match_keyword("end") {
+            break;
+        }
+
+        if !input.match_keyword("case") {
+            error!("E0001", "expected token \"case\"");
+        }
+
+        // The default case, which can't be mistaken for the else of an
+        // if statement ending the previous case
+        if input.match_keyword("else") {
+            parse_case_body(input, prog);
+
+            if !input.match_keyword("end") {
+                error!("E0001", "expected token \"end\"");
+            }
+            break;
+        }
+
+        // Compare against each value of the case, jumping to the body on
+        // a match, or to the next case if none of them match
+        let mut match_slots = Vec::new();
+        let next_case_slot = loop
+        {
+            input.eat_ws();
+            let case_pos = input.pos;
+            let const_insn_idx = prog.insns.len();
+            parse_expr(input, prog);
+            let case_val = match prog.take_const(const_insn_idx) {
+                Some(case_val) => case_val,
+                None => {
+                    input.pos = case_pos;
+                    error!("E0017", "case value must be a constant\n");
+                }
+            };
+
+            prog.append_insn_imm(Op::GetLocal, Value::Idx(value_idx));
+            prog.append_insn_imm(Op::Push, case_val);
+            prog.append_insn(Op::Equal);
+
+            if !input.match_token(",") {
+                break prog.reserve_slot();
+            }
+
+            match_slots.push(prog.reserve_slot());
+        };
+
+        for slot_idx in match_slots {
+            prog.patch_jump(slot_idx, Op::IfTrue, prog.insns.len());
+        }
+
+        parse_case_body(input, prog);
+        end_slots.push(prog.reserve_slot());
+        prog.patch_jump(next_case_slot, Op::IfNot, prog.insns.len());
+    }
+    input.leave_nested();
+
+    for slot_idx in end_slots {
+        prog.patch_jump(slot_idx, Op::Jump, prog.insns.len());
+    }
+}
+
+/// Parse the statements of a select case, up to the next case or end
+fn parse_case_body(input: &mut Input, prog: &mut Program)
+{
+    loop
+    {
+        if input.peek_keyword("case") || input.peek_keyword("end") {
+            break;
+        }
+
+        parse_stmt(input, prog);
+    }
+}
+
+/// Compound assignment operators, along with the opcode they apply
+const COMPOUND_OPS: [(&str, Op); 3] = [
+    ("+=", Op::Add),
+    ("-=", Op::Sub),
+    ("*=", Op::Mul),
+];
+
+/// Parse a statement
+fn parse_stmt(input: &mut Input, prog: &mut Program)
+{
+    // Consume whitespace
+    input.eat_ws();
+    let stmt_pos = input.pos;
+
+    // Single-line comments
+    if input.match_token("#") {
+        input.eat_comment();
+        return;
+    }
+
+    // Local variable declaration
+    if input.match_keyword("let") {
+        // Parse the variable name
+        input.eat_ws();
+        let ident_pos = input.pos;
+        let ident_str = input.parse_ident();
+        let ident = prog.symbols.intern(&ident_str);
+
+        input.expect_token("=");
+
+        // Parse the expression we are assigning
+        parse_expr(input, prog);
+
+        // Make sure this isn't a redeclaration
+        let local_idx = prog.find_local(ident);
+
+        if local_idx.is_some() {
+            input.pos = ident_pos;
+            error!("E0005", "local variable \"{}\" already declared\n", ident_str);
+        }
+
+        // Create a new local variable
+        let local_idx = prog.declare_local(ident);
+
+        // Set the local to the expression's value
+        prog.append_insn_imm(Op::SetLocal, Value::Idx(local_idx));
+
+        return;
+    }
+
+    if input.match_keyword("if") {
+        parse_if(input, prog);
+        return;
+    }
+
+    // Multi-way branch on a value
+    if input.match_keyword("select") {
+        parse_select(input, prog);
+        return;
+    }
+
+    // Function declaration
+    if input.match_keyword("fun") {
+        parse_fun(input, prog);
+        return;
+    }
+
+    // Counted loop, eg for i = 1 to 10 step 2 ... end
+    if input.match_keyword("for") {
+        // Parse the loop variable, declaring it if needed
+        let ident_str = input.parse_ident();
+        let ident = prog.symbols.intern(&ident_str);
+        let local_idx = match prog.find_local(ident) {
+            Some(idx) => idx,
+            None => prog.declare_local(ident),
+        };
+
+        // Initialize the loop variable
+        input.expect_token("=");
+        parse_expr(input, prog);
+        prog.append_insn_imm(Op::SetLocal, Value::Idx(local_idx));
+
+        // The limit is evaluated once, before the loop starts
+        if !input.match_keyword("to") {
+            error!("E0001", "expected token \"to\"");
+        }
+        let limit_idx = prog.declare_temp();
+        parse_expr(input, prog);
+        prog.append_insn_imm(Op::SetLocal, Value::Idx(limit_idx));
+
+        // The step must be a constant so we know which way to compare
+        let step = if input.match_keyword("step") {
+            input.eat_ws();
+            let step_pos = input.pos;
+            let step_insn_idx = prog.insns.len();
+            parse_expr(input, prog);
+            match prog.take_const(step_insn_idx) {
+                Some(Value::IntVal(step)) if step != 0 => step,
+                Some(Value::IntVal(0)) => {
+                    input.pos = step_pos;
+                    error!("E0008", "for loop step cannot be zero\n");
+                }
+                _ => {
+                    input.pos = step_pos;
+                    error!("E0008", "for loop step must be an integer constant\n");
+                }
+            }
+        } else {
+            1
+        };
+
+        // Skip the loop if the variable already starts past the limit
+        if step > 0 {
+            prog.append_insn_imm(Op::GetLocal, Value::Idx(limit_idx));
+            prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx));
+        } else {
+            prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx));
+            prog.append_insn_imm(Op::GetLocal, Value::Idx(limit_idx));
+        }
+        prog.append_insn(Op::LessThan);
+        let exit_slot = prog.reserve_slot();
+
+        // Parse the body of the loop
+        let body_idx = prog.insns.len();
+        prog.loops.push(LoopInfo::default());
+        input.enter_nested();
+        loop
+        {
+            if input.match_keyword("end") {
+                break;
+            }
+
+            parse_stmt(input, prog);
+        }
+        input.leave_nested();
+
+        // Step the loop variable and jump back to the body, in one
+        // instruction since this is the hottest part of most loops
+        prog.patch_continues();
+        let loop_idx = prog.for_loops.len();
+        prog.for_loops.push(ForLoopInfo {
+            local_idx: local_idx,
+            limit_idx: limit_idx,
+            step: step,
+            body_idx: body_idx,
+        });
+        prog.append_insn_imm(Op::ForLoop, Value::Idx(loop_idx));
+
+        // Patch the loop exit to jump after the loop
+        prog.patch_jump(exit_slot, Op::IfTrue, prog.insns.len());
+        prog.end_loop();
+
+        return;
+    }
+
+    // Repeat the body a given number of times, eg repeat 3 times ... end
+    if input.match_keyword("repeat") {
+        // The count is evaluated once, into a hidden counter
+        let counter_idx = prog.declare_temp();
+        parse_expr(input, prog);
+        prog.append_insn_imm(Op::SetLocal, Value::Idx(counter_idx));
+
+        // The times keyword is optional, eg repeat 3 ... end
+        input.match_keyword("times");
+
+        // Exit the loop once the counter reaches zero
+        let test_insn_idx = prog.insns.len();
+        prog.append_insn_imm(Op::GetLocal, Value::Idx(counter_idx));
+        prog.append_insn_imm(Op::Push, Value::IntVal(0));
+        prog.append_insn(Op::GreaterThan);
+        let exit_slot = prog.reserve_slot();
+
+        // Parse the body of the loop
+        prog.loops.push(LoopInfo::default());
+        input.enter_nested();
+        loop
+        {
+            if input.match_keyword("end") {
+                break;
+            }
+
+            parse_stmt(input, prog);
+        }
+        input.leave_nested();
+
+        // Decrement the counter
+        prog.patch_continues();
+        prog.append_insn_imm(Op::GetLocal, Value::Idx(counter_idx));
+        prog.append_insn_imm(Op::Push, Value::IntVal(1));
+        prog.append_insn(Op::Sub);
+        prog.append_insn_imm(Op::SetLocal, Value::Idx(counter_idx));
+
+        // Jump back to the loop test
+        prog.append_jump(Op::Jump, test_insn_idx);
+
+        // Patch the loop exit to jump after the loop
+        prog.patch_jump(exit_slot, Op::IfNot, prog.insns.len());
+        prog.end_loop();
+
+        return;
+    }
+
+    // Exit the innermost enclosing loop, or skip to its next iteration
+    for keyword in ["break", "continue"] {
+        if input.match_keyword(keyword) {
+            let slot_idx = prog.insns.len();
+
+            match prog.loops.last_mut() {
+                Some(loop_info) if keyword == "break" => loop_info.break_slots.push(slot_idx),
+                Some(loop_info) => loop_info.continue_slots.push(slot_idx),
+                None => {
+                    input.pos = stmt_pos;
+                    error!("E0015", "{} statement outside of a loop\n", keyword);
+                }
+            }
+
+            prog.append_insn(Op::Nop);
+            return;
+        }
+    }
+
+    // Label that goto statements can jump to, eg label top:
+    if input.match_keyword("label") {
+        let label_pos = input.pos;
+        let label_str = input.parse_ident();
+        let label = prog.symbols.intern(&label_str);
+        input.expect_token(":");
+
+        if prog.labels.contains_key(&label) {
+            input.pos = label_pos;
+            error!("E0016", "label \"{}\" already defined\n", label_str);
+        }
+
+        prog.labels.insert(label, prog.insns.len());
+        return;
+    }
+
+    // Jump to a label, which may come later in the code. With gosub, a
+    // return statement without a value jumps back after the gosub.
+    for (keyword, op) in [("goto", Op::Jump), ("gosub", Op::Gosub)] {
+        if input.match_keyword(keyword) {
+            // A return without a value in a function returns none, so a
+            // subroutine there could never go back after the gosub
+            if op == Op::Gosub && prog.in_fun {
+                input.pos = stmt_pos;
+                error!("E0019", "gosub inside of a function\n");
+            }
+
+            let label_pos = input.pos;
+            let label_str = input.parse_ident();
+            let label = prog.symbols.intern(&label_str);
+            let slot_idx = prog.reserve_slot();
+
+            prog.gotos.push(PendingGoto {
+                op: op,
+                label: label,
+                slot_idx: slot_idx,
+                pos: label_pos,
+            });
+            return;
+        }
+    }
+
+    // Loop with the test at the end, so the body runs at least once,
+    // eg do ... until x > 10
+    if input.match_keyword("do") {
+        let body_idx = prog.insns.len();
+
+        // Parse the body of the loop
+        prog.loops.push(LoopInfo::default());
+        input.enter_nested();
+        loop
+        {
+            if input.match_keyword("until") {
+                break;
+            }
+
+            parse_stmt(input, prog);
+        }
+        input.leave_nested();
+
+        // Go back to the start of the body while the test is false
+        prog.patch_continues();
+        parse_expr(input, prog);
+        prog.append_jump(Op::IfNot, body_idx);
+        prog.end_loop();
+
+        return;
+    }
+
+    // Sequencing of statements
+    if input.match_keyword("begin") {
+        input.enter_nested();
+
+        loop
+        {
+            if input.match_keyword("end") {
+                break;
+            }
+
+            parse_stmt(input, prog);
+        }
+
+        input.leave_nested();
+        return;
+    }
+
+    // Evaluate an expression and discard its value
+    if input.match_keyword("_") {
+        input.expect_token("=");
+        parse_expr(input, prog);
+        prog.append_insn(Op::Pop);
+        return;
+    }
+
+    // Print to stdout
+    if input.match_keyword("print") {
+        parse_expr(input, prog);
+        prog.append_insn_imm(Op::HostCall, Value::Idx(host_fn_idx("print")));
+        return;
+    }
+
+    // Return from a function, or exit the program at the top level,
+    // with the expression's value as the result
+    if input.match_keyword("return") {
+        // Without a value, return none from a function, or return from
+        // a gosub subroutine at the top level
+        if input.at_stmt_end(stmt_pos) {
+            if prog.in_fun {
+                prog.append_insn_imm(Op::Push, Value::None);
+                prog.append_insn(Op::Return);
+            } else {
+                prog.append_insn(Op::SubReturn);
+            }
+            return;
+        }
+
+        parse_expr(input, prog);
+
+        if prog.in_fun {
+            prog.append_insn(Op::Return);
+        } else {
+            prog.append_insn(Op::Exit);
+        }
+
+        return;
+    }
+
+    // Print an expression along with its value, for debugging
+    if input.match_keyword("trace") {
+        // Push the source text of the expression, patched in once parsed
+        let push_insn_idx = prog.insns.len();
+        prog.append_insn(Op::Push);
+
+        let expr_text = parse_expr_text(input, prog);
+        prog.insns[push_insn_idx].imm = Value::Str(expr_text);
+
+        prog.append_insn_imm(Op::HostCall, Value::Idx(host_fn_idx("trace")));
+        return;
+    }
+
+    // Assert that two expressions have equal values
+    if input.match_keyword("assert_eq") {
+        input.eat_ws();
+        let location = format!("{}:{}", input.file_name, input.line_at(input.pos));
+
+        let left_text = parse_expr_text(input, prog);
+        input.expect_token(",");
+        let right_text = parse_expr_text(input, prog);
+
+        let assert_idx = prog.assert_eqs.len();
+        prog.assert_eqs.push(AssertEqInfo {
+            location: location,
+            left_text: left_text,
+            right_text: right_text,
+        });
+        prog.append_insn_imm(Op::AssertEq, Value::Idx(assert_idx));
+
+        return;
+    }
+
+    // Assert that an expression evaluates to true
+    if input.match_keyword("assert") {
+        // Parse the condition
+        parse_expr(input, prog);
+
+        // If the result is true, jump over the error instruction
+        prog.append_insn_imm(Op::IfTrue, Value::IntVal(1));
+
+        // Exit with an error
+        prog.append_insn(Op::Error);
+
+        return;
+    }
+
+    let ch = input.peek_char();
+    if ch.is_alphabetic() || ch == '_' {
+        let ident_str = input.parse_ident();
+
+        // Call a function and discard its result
+        if input.match_token("(") {
+            parse_call(input, prog, &ident_str);
+            prog.append_insn(Op::Pop);
+            return;
+        }
+
+        // Write to an array element, eg a[i] = 1 or a[i][j] = 1
+        if input.match_token("[") {
+            let local_idx = expect_local(input, prog, &ident_str, stmt_pos);
+            prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx));
+
+            loop
+            {
+                parse_expr(input, prog);
+                input.expect_token("]");
+
+                if !input.match_token("[") {
+                    break;
+                }
+
+                prog.append_insn(Op::GetElem);
+            }
+
+            input.expect_token("=");
+            parse_expr(input, prog);
+            prog.append_insn(Op::SetElem);
+            return;
+        }
+
+        // Update an existing local variable, eg x += 1
+        for (op_str, op) in COMPOUND_OPS {
+            if input.match_token(op_str) {
+                let local_idx = expect_local(input, prog, &ident_str, stmt_pos);
+                prog.append_insn_imm(Op::GetLocal, Value::Idx(local_idx));
+                parse_expr(input, prog);
+                prog.append_insn(op);
+                prog.append_insn_imm(Op::SetLocal, Value::Idx(local_idx));
+                return;
+            }
+        }
+
+        // Assign to an existing local variable, eg x = x + 1
+        if input.match_token("=") {
+            let local_idx = expect_local(input, prog, &ident_str, stmt_pos);
+            parse_expr(input, prog);
+            prog.append_insn_imm(Op::SetLocal, Value::Idx(local_idx));
+            return;
+        }
+
+        // If this looks like a misspelled keyword, suggest the closest one
+        let suggestion = closest_match(&ident_str, STMT_KEYWORDS.iter().copied());
+
+        if suggestion.is_some() {
+            input.pos = stmt_pos;
+            error!(
+                "E0006",
+                "invalid statement \"{}\"{}\n",
+                ident_str,
+                did_you_mean(suggestion)
+            );
+        }
+    }
+
+    // TODO: report more info about the next token
+    input.pos = stmt_pos;
+    error!("E0006", "invalid statement");
+}
+
+/// Extract the ```weebasic code blocks out of a markdown document
+/// Lines outside of code blocks are blanked so line numbers are preserved
+fn extract_md_code(md_str: &str) -> String
+{
+    let mut code = String::new();
+
+    // Are we inside a fenced block, and is it weebasic code
+    let mut in_fence = false;
+    let mut is_code = false;
+
+    for line in md_str.lines()
+    {
+        if let Some(info) = line.trim().strip_prefix("```") {
+            if in_fence {
+                in_fence = false;
+                is_code = false;
+            } else {
+                in_fence = true;
+                is_code = info.trim() == "weebasic";
+            }
+        } else if is_code {
+            code.push_str(line);
+        }
+
+        code.push('\n');
+    }
+
+    return code;
+}
+
+/// Parse a source file into a sequence of instructions
+fn parse_file(file_name: &str, max_depth: usize) -> Program
+{
+    let mut input_str = match fs::read_to_string(file_name) {
+        Ok(input_str) => input_str,
+        Err(err) => error!("E0018", "couldn't read source file \"{}\": {}\n", file_name, err),
+    };
+
+    // Literate scripts, only the weebasic code blocks get executed
+    if file_name.ends_with(".md") {
+        input_str = extract_md_code(&input_str);
+    }
+
+    return parse_str(input_str, file_name, max_depth);
+}
+
+/// Parse source code into a sequence of instructions, where the file
+/// name is only used to report the location of errors
+fn parse_str(input_str: String, file_name: &str, max_depth: usize) -> Program
+{
+    // Input to be parsed
+    let mut input = Input::new(input_str, file_name);
+    input.max_depth = max_depth;
+
+    // Program being compiled
+    let mut program: Program = Program::new();
+
+    // Until we reach the end of the input
+    let result = panic::catch_unwind(AssertUnwindSafe(|| loop
+    {
+        // Skip trailing whitespace after the last statement
+        input.eat_ws();
+
+        // End of input
+        if input.peek_char() == '\0' {
+            resolve_gotos(&mut input, &mut program);
+            break;
+        }
+
+        parse_stmt(&mut input, &mut program);
+    }));
+
+    // Point at the source location where parsing failed
+    if let Err(payload) = result {
+        let pos = input.pos.min(input.chars.len());
+        input.print_location(pos);
+        panic::resume_unwind(payload);
+    }
+
+    program.code = Bytecode::encode(&program.insns);
+
+    return program;
+}
+
+// A quick immitation of env_logger crate that enables logging with RUST_LOG=debug
+macro_rules! debug {
+    ($($arg:tt)+) => (if env::var("RUST_LOG").is_ok() { println!($($arg)+) })
+}
+
+/// Host operation callable from bytecode, which takes its arguments
+/// from the VM's stack and pushes its result there
+type HostFn = fn(&mut VM);
+
+/// Capabilities that host operations need, which embedders can deny
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Cap
+{
+    Stdin,
+    Stdout,
+}
+
+/// All capabilities, along with their names
+const ALL_CAPS: [(&str, Cap); 2] = [
+    ("stdin", Cap::Stdin),
+    ("stdout", Cap::Stdout),
+];
+
+impl Cap
+{
+    fn name(self) -> &'static str
+    {
+        ALL_CAPS.iter().find(|(_, cap)| *cap == self).unwrap().0
+    }
+}
+
+/// Parse a comma-separated list of capability names
+fn parse_caps(cap_names: &str) -> Option<Vec<Cap>>
+{
+    let mut caps = Vec::new();
+
+    for cap_name in cap_names.split(',').filter(|name| !name.is_empty())
+    {
+        let (_, cap) = ALL_CAPS.iter().find(|(name, _)| *name == cap_name)?;
+        caps.push(*cap);
+    }
+
+    return Some(caps);
+}
+
+/// Default host operations, with the capability each one needs,
+/// indexed by HostCall instructions
+const HOST_FNS: [(&str, Cap, HostFn); 3] = [
+    ("read_int", Cap::Stdin, host_read_int),
+    ("print", Cap::Stdout, host_print),
+    ("trace", Cap::Stdout, host_trace),
+];
+
+/// Find the index of a host operation by name
+fn host_fn_idx(name: &str) -> usize
+{
+    match HOST_FNS.iter().position(|(fn_name, _, _)| *fn_name == name) {
+        Some(idx) => idx,
+        None => panic!("unknown host operation \"{}\"", name),
+    }
+}
+
+/// Read an integer value from stdin, asking again until it is valid
+fn host_read_int(vm: &mut VM)
+{
+    loop
+    {
+        println!("Input an integer value:");
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).unwrap() == 0 {
+            error!("E0112", "end of input while reading an integer\n");
+        }
+
+        match parse_int_input(&input) {
+            Some(n) => {
+                vm.push(Value::IntVal(n));
+                return;
+            }
+            None => println!("invalid integer \"{}\", try again", input.trim()),
+        }
+    }
+}
+
+/// Parse an integer entered by the user, allowing surrounding whitespace,
+/// a sign and underscores between digits, eg " -1_000 "
+fn parse_int_input(input: &str) -> Option<i64>
+{
+    let chars: Vec<char> = input.trim().chars().collect();
+
+    // Underscores can only separate digits
+    for (idx, ch) in chars.iter().enumerate()
+    {
+        if *ch == '_' {
+            let prev_ch = if idx > 0 { chars[idx - 1] } else { '\0' };
+            let next_ch = chars.get(idx + 1).copied().unwrap_or('\0');
+
+            if !prev_ch.is_digit(10) || !next_ch.is_digit(10) {
+                return None;
+            }
+        }
+    }
+
+    let num_str: String = chars.iter().filter(|ch| **ch != '_').collect();
+
+    match num_str.parse::<i64>() {
+        Ok(n) => Some(n),
+        Err(err) => match err.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                error!("E0111", "integer {} is out of range\n", input.trim());
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Print a value to stdout (followed by a newline)
+fn host_print(vm: &mut VM)
+{
+    let val = vm.pop();
+
+    match val {
+        Value::IntVal(int_val) => println!("print: {}\n", format_int(int_val, vm.thousands_sep)),
+        Value::Float(float_val) => println!("print: {}\n", format_float(float_val, vm.thousands_sep, vm.decimal_point)),
+        // Format the value first, so an error doesn't print half a line
+        _ => {
+            let val_str = val.to_string();
+            println!("print: {}\n", val_str);
+        }
+    }
+}
+
+/// Format an integer, optionally grouping digits by thousands
+fn format_int(int_val: i64, thousands_sep: Option<char>) -> String
+{
+    let sep = match thousands_sep {
+        Some(sep) => sep,
+        None => return int_val.to_string(),
+    };
+
+    let mut out = String::new();
+
+    if int_val < 0 {
+        out.push('-');
+    }
+
+    out.push_str(&group_digits(&int_val.unsigned_abs().to_string(), sep));
+    return out;
+}
+
+/// Insert a separator before each group of three digits
+fn group_digits(digits: &str, sep: char) -> String
+{
+    let mut out = String::new();
+
+    for (i, digit) in digits.chars().enumerate()
+    {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(sep);
+        }
+
+        out.push(digit);
+    }
+
+    return out;
+}
+
+/// Format a float with a given decimal point, optionally grouping the
+/// digits of its integer part by thousands
+fn format_float(float_val: f64, thousands_sep: Option<char>, decimal_point: char) -> String
+{
+    let float_str = format!("{:?}", float_val);
+
+    // Digits are only grouped outside of scientific notation, eg not in 1e23
+    let (int_part, frac_part) = match (float_str.find('.'), thousands_sep) {
+        (Some(dot_idx), Some(_)) if !float_str.contains('e') => float_str.split_at(dot_idx),
+        _ => return float_str.replace('.', &decimal_point.to_string()),
+    };
+
+    let mut out = String::new();
+    let digits = match int_part.strip_prefix('-') {
+        Some(digits) => {
+            out.push('-');
+            digits
+        }
+        None => int_part,
+    };
+
+    out.push_str(&group_digits(digits, thousands_sep.unwrap()));
+    out.push(decimal_point);
+    out.push_str(&frac_part[1..]);
+    return out;
+}
+
+/// Print an expression's source text along with its value
+fn host_trace(vm: &mut VM)
+{
+    let val = vm.pop();
+    let expr_text = vm.pop();
+    println!("{} = {}", expr_text, val);
+}
+
+/// Check that an index is within the bounds of an array of a given length
+fn check_bounds(idx: i64, len: usize) -> usize
+{
+    if idx < 0 || idx as usize >= len {
+        error!("E0113", "index {} is out of bounds for an array of length {}\n", idx, len);
+    }
+
+    return idx as usize;
+}
+
+/// Get the result of an integer operation, which is None if it overflowed
+fn check_overflow(result: Option<i64>) -> i64
+{
+    match result {
+        Some(int_val) => int_val,
+        None => error!("E0118", "integer overflow\n"),
+    }
+}
+
+/// State of the VM after running for a limited number of instructions
+#[derive(Debug)]
+enum RunState
+{
+    /// The program is done running and produced a result value
+    Done(Value),
+
+    /// The instruction budget ran out, execution can be resumed
+    Paused,
+}
+
+/// Default limit on the number of nested function calls
+const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// Activation record for a function call
+#[derive(Debug)]
+struct Frame
+{
+    /// Index of the function being called
+    fun_idx: usize,
+
+    /// Instruction to return to in the caller
+    ret_addr: usize,
+
+    /// Index in VM::locals of the function's first local variable
+    locals_base: usize,
+}
+
+/// Summary of a program run, eg for autograders ranking solutions
+#[derive(Debug)]
+struct RunReport
+{
+    /// Exit status of the process
+    exit_status: u8,
+
+    /// Total number of instructions executed
+    insns_executed: u64,
+
+    /// Largest number of values on the stack at any point
+    peak_stack_depth: usize,
+
+    /// Time taken to parse and run the program
+    wall_time: Duration,
+
+    /// Number of times each opcode was executed, indexed by opcode
+    op_counts: Vec<u64>,
+}
+
+impl RunReport
+{
+    /// Format the report as a JSON object, only listing executed opcodes
+    fn to_json(&self) -> String
+    {
+        let op_counts: Vec<String> = self.op_counts.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(op_idx, count)| format!("\"{}\": {}", Op::from_index(op_idx).info().name, count))
+            .collect();
+
+        format!(
+            "{{\"exit_status\": {}, \"insns_executed\": {}, \"peak_stack_depth\": {}, \"wall_time_us\": {}, \"op_counts\": {{{}}}}}",
+            self.exit_status,
+            self.insns_executed,
+            self.peak_stack_depth,
+            self.wall_time.as_micros(),
+            op_counts.join(", ")
+        )
+    }
+}
+
+/// Virtual machine / interpreter
+struct VM
+{
+    /// Local variables of the top level, followed by those of each frame
+    locals: Vec<Value>,
+
+    /// Stack of temporary values
+    stack: Vec<Value>,
+
+    /// Program counter
+    pc: usize,
+
+    /// Frames of the functions being called
+    frames: Vec<Frame>,
+
+    /// Return addresses of the gosub subroutines being run, along with
+    /// the number of call frames active when each gosub was made
+    gosub_stack: Vec<usize>,
+
+    /// Maximum number of nested function calls, to catch runaway recursion
+    max_call_depth: usize,
+
+    /// When profiling, how many times each pair of instructions was
+    /// executed one after the other, to find candidate superinstructions
+    pair_counts: Option<HashMap<(Op, Op), u64>>,
+
+    /// Index of the previously executed instruction, when profiling
+    prev_pc: Option<usize>,
+
+    /// When reporting, how many times each opcode was executed
+    op_counts: Option<Vec<u64>>,
+
+    /// Largest number of values on the stack, when reporting
+    peak_stack_depth: usize,
+
+    /// Host operations called by HostCall instructions
+    host_fns: Vec<HostFn>,
+
+    /// Capabilities host operations are allowed to use
+    allowed_caps: Vec<Cap>,
+
+    /// Character used to group digits by thousands when printing numbers
+    thousands_sep: Option<char>,
+
+    /// Character separating the integer and fractional parts when printing floats
+    decimal_point: char,
+}
+
+impl VM
+{
+    fn new() -> Self {
+        VM {
+            locals: Vec::default(),
+            stack: Vec::default(),
+            pc: 0,
+            frames: Vec::default(),
+            gosub_stack: Vec::default(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            pair_counts: None,
+            prev_pc: None,
+            op_counts: None,
+            peak_stack_depth: 0,
+            host_fns: HOST_FNS.iter().map(|(_, _, host_fn)| *host_fn).collect(),
+            allowed_caps: ALL_CAPS.iter().map(|(_, cap)| *cap).collect(),
+            thousands_sep: None,
+            decimal_point: '.',
+        }
+    }
+
+    /// Create a VM where host operations can only use the given capabilities
+    fn with_caps(caps: &[Cap]) -> Self
+    {
+        let mut vm = VM::new();
+        vm.allowed_caps = caps.to_vec();
+        return vm;
+    }
+
+    /// Override a host operation, eg to redirect I/O when embedding the VM
+    fn set_host_fn(&mut self, name: &str, host_fn: HostFn)
+    {
+        self.host_fns[host_fn_idx(name)] = host_fn;
+    }
+
+    fn push(&mut self, val: Value) {
+        self.stack.push(val);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    /// Index in locals of the current function's first local variable
+    fn locals_base(&self) -> usize {
+        match self.frames.last() {
+            Some(frame) => frame.locals_base,
+            None => 0,
+        }
+    }
+
+    /// Call a function, with its arguments on top of the stack
+    fn call(&mut self, prog: &Program, fun_idx: usize)
+    {
+        let fun = &prog.funs[fun_idx];
+
+        if self.frames.len() >= self.max_call_depth {
+            error!("E0105", "maximum call depth of {} exceeded\n", self.max_call_depth);
+        }
+
+        // The function's locals go after those of the caller
+        let locals_base = self.locals.len();
+        self.locals.resize(locals_base + fun.num_locals, Value::None);
+
+        // Move the arguments into the parameter locals
+        let args_start = self.stack.len() - fun.num_params;
+        for (i, arg) in self.stack.drain(args_start..).enumerate() {
+            self.locals[locals_base + i] = arg;
+        }
+
+        self.frames.push(Frame {
+            fun_idx: fun_idx,
+            ret_addr: self.pc,
+            locals_base: locals_base,
+        });
+
+        self.pc = fun.entry_idx;
+    }
+
+    // Evaluate/run a program, producing the program's result value
+    fn eval(&mut self, prog: &Program) -> Value
+    {
+        self.init(prog);
+
+        loop
+        {
+            if let RunState::Done(result) = self.run_for(prog, usize::MAX) {
+                return result;
+            }
+        }
+    }
+
+    /// Print the chain of function calls leading to the current instruction
+    fn print_stack_trace(&self, prog: &Program)
+    {
+        eprintln!("stack trace, most recent call first:");
+
+        // Deep recursion can produce huge traces, so only show the top
+        const MAX_SHOWN: usize = 20;
+
+        let mut pc = self.pc;
+
+        for (depth, frame) in self.frames.iter().rev().enumerate()
+        {
+            if depth == MAX_SHOWN {
+                eprintln!("  ... {} more calls", self.frames.len() - MAX_SHOWN);
+                return;
+            }
+
+            let fun_name = prog.symbols.name(prog.funs[frame.fun_idx].name);
+            eprintln!("  in {}, at instruction {}", fun_name, pc);
+            pc = frame.ret_addr;
+        }
+
+        eprintln!("  at top level, at instruction {}", pc);
+    }
+
+    /// Print the most frequently executed instruction pairs, which are
+    /// the best candidates to be fused into superinstructions
+    fn print_pair_profile(&self)
+    {
+        const MAX_SHOWN: usize = 15;
+
+        let pair_counts = match &self.pair_counts {
+            Some(pair_counts) => pair_counts,
+            None => return,
+        };
+
+        let total: u64 = pair_counts.values().sum();
+        let mut pairs: Vec<_> = pair_counts.iter().collect();
+        pairs.sort_by(|a, b| b.1.cmp(a.1));
+
+        eprintln!("instruction pairs executed in sequence, most frequent first:");
+        eprintln!("{:>12} {:>7}  pair", "count", "%");
+
+        for ((op0, op1), count) in pairs.iter().take(MAX_SHOWN)
+        {
+            let percent = 100.0 * (**count as f64) / (total as f64);
+            eprintln!("{:>12} {:>6.2}%  {} {}", count, percent, op0.info().name, op1.info().name);
+        }
+    }
+
+    /// Summarize the last run, once the program is done
+    fn run_report(&self, exit_status: u8, wall_time: Duration) -> RunReport
+    {
+        let op_counts = match &self.op_counts {
+            Some(op_counts) => op_counts.clone(),
+            None => vec![0; ALL_OPS.len()],
+        };
+
+        RunReport {
+            exit_status: exit_status,
+            insns_executed: op_counts.iter().sum(),
+            peak_stack_depth: self.peak_stack_depth,
+            wall_time: wall_time,
+            op_counts: op_counts,
+        }
+    }
+
+    /// Prepare the VM to start running a program from the beginning
+    fn init(&mut self, prog: &Program)
+    {
+        self.locals.clear();
+        self.locals.resize(prog.num_locals, Value::None);
+        self.stack.clear();
+        self.frames.clear();
+        self.gosub_stack.clear();
+        self.prev_pc = None;
+        self.peak_stack_depth = 0;
+        self.pc = 0;
+    }
+
+    /// Run a program for at most a given number of instructions, so that
+    /// embedders can interleave execution with other work. The VM must be
+    /// initialized with init() first, and again after the program is done.
+    fn run_for(&mut self, prog: &Program, budget: usize) -> RunState
+    {
+        for _ in 0..budget {
+            // Running past the last instruction is a successful exit
+            if self.pc >= prog.code.len() {
+                return RunState::Done(Value::IntVal(0));
+            }
+
+            // Decode the current instruction
+            let (op, imm) = prog.code.decode(self.pc);
+            debug!("{:3}: {} {:?}", self.pc, op.info().name, imm);
+
+            // Count pairs of adjacent instructions executed in sequence,
+            // which are the ones that could be fused into one
+            if let Some(pair_counts) = &mut self.pair_counts {
+                if let Some(prev_pc) = self.prev_pc {
+                    if prev_pc + 1 == self.pc {
+                        let pair = (prog.code.decode(prev_pc).0, op);
+                        *pair_counts.entry(pair).or_insert(0) += 1;
+                    }
+                }
+
+                self.prev_pc = Some(self.pc);
+            }
 
-        while self.pc < prog.insns.len() {
-            // Read the current instruction
-            let insn = &prog.insns[self.pc];
-            debug!("{:3}: {:?}", self.pc, insn);
+            // Count instructions executed by opcode, for run reports
+            if let Some(op_counts) = &mut self.op_counts {
+                op_counts[op as usize] += 1;
+                self.peak_stack_depth = self.peak_stack_depth.max(self.stack.len());
+            }
 
-            match insn.op
+            match op
             {
-                // Exit the program
+                // Exit the program with a result value
                 Op::Exit => {
-                    return;
+                    return RunState::Done(self.pop());
                 }
 
                 // Abort execution
                 Op::Error => {
-                    panic!("Run-time error\n");
+                    error!("E0101", "assertion failed\n");
                 }
 
                 Op::Push => {
-                    self.push(insn.imm.clone());
+                    self.push(imm.clone());
+                }
+
+                Op::Pop => {
+                    self.pop();
+                }
+
+                // Duplicate the value on top of the stack
+                Op::Dup => {
+                    let val = self.stack.last().expect("stack is empty").clone();
+                    self.push(val);
                 }
 
                 Op::SetLocal => {
-                    self.locals[insn.imm.unwrap_idx()] = self.pop();
+                    let local_idx = self.locals_base() + imm.unwrap_idx();
+                    self.locals[local_idx] = self.pop();
                 }
 
                 Op::GetLocal => {
-                    let val = self.locals[insn.imm.unwrap_idx()].clone();
-                    assert!(!val.is_none(), "uninitialized local");
+                    let local_idx = self.locals_base() + imm.unwrap_idx();
+                    let val = self.locals[local_idx].clone();
+                    if val.is_none() {
+                        error!("E0103", "uninitialized local");
+                    }
                     self.push(val);
                 }
 
                 // Compare two values for equality
                 Op::Equal => {
-                    let arg1 = self.pop().unwrap_int();
-                    let arg0 = self.pop().unwrap_int();
-                    let bool_val = if arg0 == arg1 { 1 } else { 0 };
-                    self.push(Value::IntVal(bool_val));
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let bool_val = arg0.equals(&arg1);
+                    self.push(Value::Bool(bool_val));
+                }
+
+                Op::NotEqual => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let bool_val = !arg0.equals(&arg1);
+                    self.push(Value::Bool(bool_val));
                 }
 
                 Op::LessThan => {
-                    let arg1 = self.pop().unwrap_int();
-                    let arg0 = self.pop().unwrap_int();
-                    let bool_val = if arg0 < arg1 { 1 } else { 0 };
-                    self.push(Value::IntVal(bool_val));
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let bool_val = arg0.compare(&arg1) == Ordering::Less;
+                    self.push(Value::Bool(bool_val));
+                }
+
+                Op::LessEqual => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let bool_val = arg0.compare(&arg1) != Ordering::Greater;
+                    self.push(Value::Bool(bool_val));
+                }
+
+                Op::GreaterThan => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let bool_val = arg0.compare(&arg1) == Ordering::Greater;
+                    self.push(Value::Bool(bool_val));
+                }
+
+                Op::GreaterEqual => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let bool_val = arg0.compare(&arg1) != Ordering::Less;
+                    self.push(Value::Bool(bool_val));
                 }
 
                 // Jump if true
                 Op::IfTrue => {
-                    let test_val = self.pop().unwrap_int();
+                    let test_val = self.pop();
 
-                    if test_val != 0 {
-                        let jump_offset = insn.imm.unwrap_int();
+                    if test_val.is_truthy() {
+                        let jump_offset = imm.unwrap_int();
                         self.pc = ((self.pc as i64) + jump_offset) as usize;
                     }
                 }
 
+                Op::Jump => {
+                    let jump_offset = imm.unwrap_int();
+                    self.pc = ((self.pc as i64) + jump_offset) as usize;
+                }
+
+                // Step the loop variable, and go back to the loop body
+                // unless the variable went past the limit
+                Op::ForLoop => {
+                    let info = &prog.for_loops[imm.unwrap_idx()];
+                    let locals_base = self.locals_base();
+                    let counter = &self.locals[locals_base + info.local_idx];
+                    let limit = &self.locals[locals_base + info.limit_idx];
+
+                    // A counter that would overflow has gone past any limit,
+                    // so the loop ends and the counter keeps its last value
+                    let (next, in_range) = match (counter, limit) {
+                        (Value::IntVal(counter), Value::IntVal(limit)) => {
+                            match counter.checked_add(info.step) {
+                                Some(next) => {
+                                    let in_range = if info.step > 0 { next <= *limit } else { next >= *limit };
+                                    (Value::IntVal(next), in_range)
+                                }
+                                None => (Value::IntVal(*counter), false),
+                            }
+                        }
+                        _ => {
+                            let next = match counter {
+                                Value::Float(counter) => Some(Value::Float(counter + info.step as f64)),
+                                _ => counter.unwrap_int().checked_add(info.step).map(Value::IntVal),
+                            };
+                            match next {
+                                Some(next) => {
+                                    let past = if info.step > 0 { Ordering::Greater } else { Ordering::Less };
+                                    let in_range = next.compare(limit) != past;
+                                    (next, in_range)
+                                }
+                                None => (counter.clone(), false),
+                            }
+                        }
+                    };
+
+                    self.locals[locals_base + info.local_idx] = next;
+
+                    if in_range {
+                        self.pc = info.body_idx;
+                        continue;
+                    }
+                }
+
+                // Call a function, remembering where to return to
+                Op::Call => {
+                    self.call(prog, imm.unwrap_idx());
+                    continue;
+                }
+
+                // The number of arguments is only checked at run time,
+                // since the function isn't known at compile time
+                Op::CallIndirect => {
+                    let fun_idx = self.pop().unwrap_fun();
+                    let fun = &prog.funs[fun_idx];
+                    let num_args = imm.unwrap_idx();
+
+                    // Collect extra arguments into an array for variadic functions
+                    if fun.is_variadic && num_args >= fun.num_params - 1 {
+                        let num_extra = num_args - (fun.num_params - 1);
+                        let extra_args = self.stack.split_off(self.stack.len() - num_extra);
+                        self.push(Value::Array(Rc::new(RefCell::new(extra_args))));
+                    } else if fun.is_variadic {
+                        error!(
+                            "E0114",
+                            "function \"{}\" takes at least {} arguments but {} were given\n",
+                            prog.symbols.name(fun.name),
+                            fun.num_params - 1,
+                            num_args
+                        );
+                    } else if num_args != fun.num_params {
+                        error!(
+                            "E0114",
+                            "function \"{}\" takes {} arguments but {} were given\n",
+                            prog.symbols.name(fun.name),
+                            fun.num_params,
+                            num_args
+                        );
+                    }
+
+                    self.call(prog, fun_idx);
+                    continue;
+                }
+
+                // Return to the caller, leaving the result on the stack
+                Op::Return => {
+                    let frame = self.frames.pop().expect("return outside of a function");
+                    self.locals.truncate(frame.locals_base);
+                    self.pc = frame.ret_addr;
+                }
+
+                // Jump to a subroutine, remembering where to return to
+                Op::Gosub => {
+                    if self.gosub_stack.len() >= self.max_call_depth {
+                        error!("E0105", "maximum call depth of {} exceeded\n", self.max_call_depth);
+                    }
+
+                    self.gosub_stack.push(self.pc);
+                    let jump_offset = imm.unwrap_int();
+                    self.pc = ((self.pc as i64) + jump_offset) as usize;
+                }
+
+                Op::SubReturn => {
+                    self.pc = match self.gosub_stack.pop() {
+                        Some(ret_addr) => ret_addr,
+                        None => error!("E0116", "return without gosub\n"),
+                    };
+                }
+
                 // Jump if not true
                 Op::IfNot => {
-                    let test_val = self.pop().unwrap_int();
+                    let test_val = self.pop();
 
-                    if test_val == 0 {
-                        let jump_offset = insn.imm.unwrap_int();
+                    if !test_val.is_truthy() {
+                        let jump_offset = imm.unwrap_int();
                         self.pc = ((self.pc as i64) + jump_offset) as usize;
                     }
                 }
 
+                // Adding a string to any value concatenates them,
+                // converting the other value to a string
                 Op::Add => {
-                    let arg1 = self.pop().unwrap_int();
-                    let arg0 = self.pop().unwrap_int();
-                    self.push(Value::IntVal(arg0 + arg1));
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let result = match (&arg0, &arg1) {
+                        (Value::Str(_), _) | (_, Value::Str(_)) => Value::Str(format!("{}{}", arg0, arg1)),
+                        (Value::Float(_), _) | (_, Value::Float(_)) => Value::Float(arg0.to_float() + arg1.to_float()),
+                        _ => Value::IntVal(check_overflow(arg0.unwrap_int().checked_add(arg1.unwrap_int()))),
+                    };
+                    self.push(result);
                 }
 
                 Op::Sub => {
-                    let arg1 = self.pop().unwrap_int();
-                    let arg0 = self.pop().unwrap_int();
-                    self.push(Value::IntVal(arg0 - arg1));
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+
+                    if arg0.is_float() || arg1.is_float() {
+                        self.push(Value::Float(arg0.to_float() - arg1.to_float()));
+                    } else {
+                        self.push(Value::IntVal(check_overflow(arg0.unwrap_int().checked_sub(arg1.unwrap_int()))));
+                    }
+                }
+
+                Op::Mul => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+
+                    if arg0.is_float() || arg1.is_float() {
+                        self.push(Value::Float(arg0.to_float() * arg1.to_float()));
+                    } else {
+                        self.push(Value::IntVal(check_overflow(arg0.unwrap_int().checked_mul(arg1.unwrap_int()))));
+                    }
+                }
+
+                // Float division follows IEEE 754, eg 1.0 / 0 is inf
+                Op::Div => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+
+                    if arg0.is_float() || arg1.is_float() {
+                        self.push(Value::Float(arg0.to_float() / arg1.to_float()));
+                    } else {
+                        let arg1 = arg1.unwrap_int();
+                        let arg0 = arg0.unwrap_int();
+
+                        if arg1 == 0 {
+                            error!("E0107", "division by zero\n");
+                        }
+
+                        self.push(Value::IntVal(check_overflow(arg0.checked_div(arg1))));
+                    }
+                }
+
+                Op::Mod => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+
+                    if arg0.is_float() || arg1.is_float() {
+                        self.push(Value::Float(arg0.to_float() % arg1.to_float()));
+                    } else {
+                        let arg1 = arg1.unwrap_int();
+                        let arg0 = arg0.unwrap_int();
+
+                        if arg1 == 0 {
+                            error!("E0107", "division by zero\n");
+                        }
+
+                        self.push(Value::IntVal(arg0.wrapping_rem(arg1)));
+                    }
+                }
+
+                Op::Pow => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+
+                    if arg0.is_float() || arg1.is_float() {
+                        self.push(Value::Float(arg0.to_float().powf(arg1.to_float())));
+                    } else {
+                        let arg1 = arg1.unwrap_int();
+                        let arg0 = arg0.unwrap_int();
+
+                        if arg1 < 0 {
+                            error!("E0108", "negative exponent {}\n", arg1);
+                        }
+
+                        let exponent = arg1.min(u32::MAX as i64) as u32;
+                        self.push(Value::IntVal(check_overflow(arg0.checked_pow(exponent))));
+                    }
+                }
+
+                Op::Cmp => {
+                    let arg1 = self.pop();
+                    let arg0 = self.pop();
+                    let ord_val = match arg0.compare(&arg1) {
+                        Ordering::Less => -1,
+                        Ordering::Equal => 0,
+                        Ordering::Greater => 1,
+                    };
+                    self.push(Value::IntVal(ord_val));
+                }
+
+                Op::Len => {
+                    let len = match self.pop() {
+                        Value::Array(ref elems) => elems.borrow().len(),
+                        arg0 => arg0.unwrap_str().chars().count(),
+                    };
+                    self.push(Value::IntVal(len as i64));
+                }
+
+                // Indices are clamped to the bounds of the string
+                Op::Substr => {
+                    let num_chars = self.pop().unwrap_int().max(0) as usize;
+                    let start_idx = self.pop().unwrap_int().max(0) as usize;
+                    let str_val = self.pop();
+                    let sub: String = str_val.unwrap_str().chars().skip(start_idx).take(num_chars).collect();
+                    self.push(Value::Str(sub));
+                }
+
+                Op::Find => {
+                    let needle = self.pop();
+                    let haystack = self.pop();
+                    let haystack = haystack.unwrap_str();
+
+                    // Convert the byte offset into a character index
+                    let idx = match haystack.find(needle.unwrap_str()) {
+                        Some(byte_idx) => haystack[..byte_idx].chars().count() as i64,
+                        None => -1,
+                    };
+                    self.push(Value::IntVal(idx));
+                }
+
+                Op::ToInt => {
+                    match self.pop() {
+                        Value::Bool(bool_val) => self.push(Value::IntVal(bool_val as i64)),
+                        Value::Float(float_val) => self.push(Value::IntVal(float_val as i64)),
+                        arg0 => self.push(Value::IntVal(arg0.unwrap_int())),
+                    }
+                }
+
+                Op::ToFloat => {
+                    let arg0 = self.pop();
+                    self.push(Value::Float(arg0.to_float()));
+                }
+
+                Op::Neg => {
+                    match self.pop() {
+                        Value::Float(float_val) => self.push(Value::Float(-float_val)),
+                        arg0 => self.push(Value::IntVal(check_overflow(arg0.unwrap_int().checked_neg()))),
+                    }
+                }
+
+                Op::Not => {
+                    let arg0 = self.pop();
+                    self.push(Value::Bool(!arg0.is_truthy()));
+                }
+
+                Op::NewArray => {
+                    let num_elems = imm.unwrap_idx();
+                    let elems = self.stack.split_off(self.stack.len() - num_elems);
+                    self.push(Value::Array(Rc::new(RefCell::new(elems))));
+                }
+
+                Op::GetElem => {
+                    let idx = self.pop().unwrap_int();
+                    let array = self.pop();
+                    let elems = array.unwrap_array().borrow();
+                    let elem_idx = check_bounds(idx, elems.len());
+                    let val = elems[elem_idx].clone();
+                    drop(elems);
+                    self.push(val);
+                }
+
+                Op::SetElem => {
+                    let val = self.pop();
+                    let idx = self.pop().unwrap_int();
+                    let array = self.pop();
+                    let mut elems = array.unwrap_array().borrow_mut();
+                    let elem_idx = check_bounds(idx, elems.len());
+                    elems[elem_idx] = val;
+                }
+
+                Op::ArrayPush => {
+                    let val = self.pop();
+                    let array = self.pop();
+                    let len = {
+                        let mut elems = array.unwrap_array().borrow_mut();
+                        elems.push(val);
+                        elems.len()
+                    };
+                    self.push(Value::IntVal(len as i64));
                 }
 
-                // Read an integer value from stdin
-                Op::ReadInt => {
-                    println!("Input an integer value:");
-                    print!("> ");
-                    io::stdout().flush().unwrap();
+                Op::ArrayPop => {
+                    let array = self.pop();
+                    let val = array.unwrap_array().borrow_mut().pop();
+                    match val {
+                        Some(val) => self.push(val),
+                        None => error!("E0115", "pop from an empty array\n"),
+                    }
+                }
 
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input).unwrap();
-                    let n: i64 = input.trim().parse().unwrap();
-                    self.push(Value::IntVal(n));
+                Op::AssertEq => {
+                    let right = self.pop();
+                    let left = self.pop();
+
+                    if !left.equals(&right) {
+                        let info = &prog.assert_eqs[imm.unwrap_idx()];
+                        error!(
+                            "E0110",
+                            "assertion failed at {}\n  left:  {} = {}\n  right: {} = {}\n",
+                            info.location,
+                            info.left_text,
+                            left,
+                            info.right_text,
+                            right
+                        );
+                    }
                 }
 
-                // Print a value to stdout (followed by a newline)
-                Op::Print => {
-                    let int_val = self.pop().unwrap_int();
-                    println!("print: {}\n", int_val);
+                // Call into a host operation
+                Op::HostCall => {
+                    let host_idx = imm.unwrap_idx();
+                    let (name, cap, _) = HOST_FNS[host_idx];
+
+                    if !self.allowed_caps.contains(&cap) {
+                        error!("E0106", "{} needs the {} capability, which is not allowed\n", name, cap.name());
+                    }
+
+                    let host_fn = self.host_fns[host_idx];
+                    host_fn(self);
                 }
 
+                Op::Nop => {}
+
                 #[allow(unreachable_patterns)]
                 _ => {
-                    panic!("unknown bytecode instruction in eval {:?}", insn.op);
+                    panic!("unknown bytecode instruction in eval {:?}", op);
                 }
             }
 
             // Move to the next instruction
             self.pc += 1;
         }
+
+        return RunState::Paused;
+    }
+}
+
+/// Diagnostic codes, with a short summary and an extended explanation
+const DIAGNOSTICS: [(&str, &str, &str); 38] = [
+    ("E0001", "expected token", "\
+The parser expected a specific token, such as a keyword or a parenthesis,
+but found something else. For example, an if statement needs a then:
+
+    if x < 10 print x        # error
+    if x < 10 then print x   # ok"),
+
+    ("E0002", "expected identifier", "\
+A name was expected, for example after let or fun. Names start with a
+letter or an underscore, followed by letters, digits or underscores:
+
+    let 1x = 5   # error
+    let x1 = 5   # ok"),
+
+    ("E0003", "undeclared variable", "\
+A variable was used before being declared with let. Variables must be
+declared before they can be read:
+
+    print y      # error
+    let y = 2
+    print y      # ok
+
+The same applies to assignments, which can only change the value of a
+variable that was already declared:
+
+    z = 1        # error
+    let z = 0
+    z = 1        # ok"),
+
+    ("E0004", "invalid expression", "\
+The parser expected an expression, such as a number, a variable, a call
+or a parenthesized expression, but found something else:
+
+    let x = )    # error
+    let x = (1)  # ok"),
+
+    ("E0005", "variable already declared", "\
+A variable with the same name was already declared with let. Each
+variable can only be declared once:
+
+    let x = 1
+    let x = 2    # error"),
+
+    ("E0006", "invalid statement", "\
+The parser could not recognize a statement. Statements start with a
+keyword such as let, if, for, print or assert, or are function calls:
+
+    prnt 1       # error
+    print 1      # ok"),
+
+    ("E0007", "nesting too deep", "\
+Statements or parenthesized expressions are nested more deeply than the
+parser allows. Try to split the code into functions or simplify it."),
+
+    ("E0008", "invalid for loop step", "\
+The step of a for loop must be a nonzero integer constant, so that the
+direction of the loop is known when it is compiled:
+
+    for i = 1 to 10 step 0   # error
+    for i = 1 to 10 step 2   # ok"),
+
+    ("E0009", "undeclared function", "\
+A function was called before being declared with fun. Functions must be
+declared before they are called:
+
+    fun greet()
+        print 1
+    end
+    greet()"),
+
+    ("E0010", "nested function declaration", "\
+Functions can only be declared at the top level of a program, not inside
+the body of another function."),
+
+    ("E0011", "function already declared", "\
+A function with the same name was already declared. Each function can
+only be declared once."),
+
+    ("E0012", "wrong number of arguments", "\
+A function was called with a different number of arguments than it has
+parameters:
+
+    fun add(a, b)
+        return a + b
+    end
+    print add(1)      # error
+    print add(1, 2)   # ok
+
+A variadic function needs at least one argument for each parameter
+before the trailing `...` one."),
+
+    ("E0013", "invalid string literal", "\
+A string literal must be closed with a double quote on the same line, and
+can only contain the escape sequences \\n, \\t, \\\" and \\\\:
+
+    print \"hello     # error
+    print \"hello\"    # ok"),
+
+    ("E0014", "invalid named argument", "\
+Named arguments must match a parameter of the called function, each
+parameter can only be given once, and positional arguments must come
+first. Only user-defined functions can take named arguments:
+
+    fun draw(x, y)
+        print x + y
+    end
+    draw(1, y: 2)      # ok
+    draw(y: 2, x: 1)   # ok
+    draw(x: 1, z: 2)   # error
+    draw(y: 2, 1)      # error"),
+
+    ("E0015", "break or continue outside of a loop", "\
+The break and continue statements can only be used inside the body of a
+for, repeat or do loop. They jump to the end of the innermost loop, or to
+its next iteration:
+
+    break              # error
+    for i = 1 to 10
+        if i % 2 == 0 then continue
+        if i > 7 then break
+    end                # ok"),
+
+    ("E0016", "invalid label", "\
+A goto or gosub statement must jump to a label defined with the label
+statement, and each label can only be defined once. Labels are local to
+the function they are defined in, or to the top level:
+
+    goto done          # error, no such label
+    label top:
+    label top:         # error, already defined
+    goto top           # ok"),
+
+    ("E0017", "non-constant case value", "\
+The values of a case in a select statement must be constants, such as
+numbers or strings, so that they can be compared directly:
+
+    select x
+    case 1, 2          # ok
+        print \"small\"
+    case y             # error
+        print \"y\"
+    end"),
+
+    ("E0018", "unreadable source file", "\
+The source file given on the command line doesn't exist, or couldn't be
+read, eg because of its permissions. Check the path of the file:
+
+    ./weebasic exmaple.bas    # error
+    ./weebasic example.bas    # ok"),
+
+    ("E0019", "gosub inside of a function", "\
+Gosub subroutines can only be used at the top level of the program. In a
+function, a return statement without a value returns none, so it could
+never jump back after the gosub. Call another function instead:
+
+    fun greet()
+        gosub say_hello     # error
+        print_hello()       # ok
+    end"),
+
+    ("E0101", "assertion failed", "\
+The condition of an assert statement evaluated to false at run time:
+
+    let x = 1
+    assert x == 2   # error"),
+
+    ("E0102", "type error", "\
+An operation was given a value of the wrong type, such as a string where
+an integer was expected."),
+
+    ("E0103", "uninitialized variable", "\
+A variable was read before any value was stored in it. This can happen
+when a variable is declared in a branch of code that never ran."),
+
+    ("E0104", "invalid program result", "\
+The value returned from the top level of a program becomes the exit code
+of the process, so it must be an integer between 0 and 255. Note that 1
+and 2 are also the exit codes for run-time and compile errors:
+
+    return 3     # ok
+    return 256   # error
+    return \"3\"   # error"),
+
+    ("E0105", "call depth exceeded", "\
+Too many function calls were nested, usually because of a recursive
+function that never reaches its base case:
+
+    fun forever(n)
+        return forever(n + 1)
+    end"),
+
+    ("E0106", "capability not allowed", "\
+A host operation, such as reading from stdin, needs a capability that
+was not allowed for this run. For example, this program fails when run
+with --caps=stdout, which only allows writing to stdout:
+
+    let x = read_int"),
+
+    ("E0107", "division by zero", "\
+An integer was divided by zero at run time:
+
+    let d = 0
+    print 10 / d   # error
+    print 10 % d   # error"),
+
+    ("E0108", "negative exponent", "\
+Integers can only be raised to a power of zero or more, since a negative
+power would not give an integer result:
+
+    print 2 ^ 3    # ok, prints 8
+    print 2 ^ -1   # error"),
+
+    ("E0109", "incomparable values", "\
+Ordering values, eg with < or cmp, needs two numbers, two strings, which
+are ordered lexicographically, two booleans or two functions. Values of
+other types, such as a boolean and an integer, or arrays, can't be
+ordered. Neither can nan, which is not less than, equal to or greater
+than any number. Any values can be checked for equality though:
+
+    print cmp(1, 2)       # ok, prints -1
+    print \"a\" < \"b\"       # ok, prints true
+    print 1 == \"1\"        # ok, prints false
+    print 1 < \"1\"         # error, cannot compare integer with string
+    print (1 < 2) < 3     # error, cannot compare boolean with integer
+    print 0.0 / 0 < 1     # error, cannot compare nan"),
+
+    ("E0110", "assert_eq failed", "\
+The two values given to an assert_eq statement were not equal. Both
+operands are shown, along with their values. Integers and floats are
+compared as numbers, other values of different types are never equal:
+
+    let x = 2
+    assert_eq x + 1, 3    # ok
+    assert_eq x * 2, 5    # error, left: x * 2 = 4, right: 5 = 5"),
+
+    ("E0111", "integer out of range", "\
+An integer entered for read_int doesn't fit in 64 bits, so it has to be
+between -9223372036854775808 and 9223372036854775807."),
+
+    ("E0112", "end of input", "\
+The program called read_int, but there was no more input to read, eg
+because stdin was redirected from a file that was too short."),
+
+    ("E0113", "index out of bounds", "\
+An array was indexed past its end, or with a negative index. Valid
+indices go from 0 to the length of the array minus one:
+
+    let a = [1, 2, 3]
+    print a[2]   # ok, prints 3
+    print a[3]   # error"),
+
+    ("E0114", "wrong number of arguments", "\
+A function reference was called with a different number of arguments
+than the function has parameters. Since the function isn't known until
+the program runs, this is a run-time error:
+
+    fun double(x)
+        return x * 2
+    end
+    let f = @double
+    print f(3)      # ok, prints 6
+    print f(3, 4)   # error"),
+
+    ("E0115", "empty array", "\
+The last element of an array was removed with pop, but the array was
+empty. Check the length of the array with len first:
+
+    let a = [1]
+    print pop(a)   # ok, prints 1
+    print pop(a)   # error"),
+
+    ("E0116", "return without gosub", "\
+A return statement without a value jumps back after the most recent
+gosub, but no gosub subroutine was running. This usually happens when
+the code before a subroutine doesn't stop at its end:
+
+    gosub greet
+    return 0        # ok, exits before the subroutine
+    label greet:
+    print \"hello\"
+    return          # back after gosub greet"),
+
+    ("E0117", "array nested too deeply", "\
+An array was printed, converted to a string or compared with another,
+but it contains arrays nested more than 10000 levels deep. This usually
+comes from wrapping an array in a loop:
+
+    let a = []
+    for i = 1 to 100000
+        a = [a]     # each iteration adds a level
+    end
+    print a         # error"),
+
+    ("E0118", "integer overflow", "\
+The result of an integer operation doesn't fit in 64 bits, so it can't
+be represented. Integers range from -9223372036854775808 up to
+9223372036854775807. Use floats for larger numbers:
+
+    print 2 ^ 63      # error
+    print 2.0 ^ 63    # ok, 9.223372036854776e18"),
+
+    ("W0001", "unreachable code", "\
+The condition of an if statement is a constant, so one of its branches
+can never run and was removed:
+
+    if 0 then print 1   # print 1 never runs"),
+];
+
+/// Print the extended explanation for a diagnostic code
+fn explain(code: &str) -> bool
+{
+    for (diag_code, summary, explanation) in DIAGNOSTICS
+    {
+        if diag_code == code {
+            println!("{}: {}\n", diag_code, summary);
+            println!("{}", explanation);
+            return true;
+        }
+    }
+
+    eprintln!("unknown diagnostic code \"{}\"", code);
+    return false;
+}
+
+/// Exit code for errors raised while the program runs
+const EXIT_RUNTIME_ERROR: u8 = 1;
+
+/// Exit code for errors raised while parsing the program, or bad usage
+const EXIT_COMPILE_ERROR: u8 = 2;
+
+/// Exit code for bugs in weebasic itself, same as Rust's default for panics
+const EXIT_INTERNAL_ERROR: u8 = 101;
+
+/// Check if a panic was raised with error!(), as opposed to an internal error
+fn is_diagnostic(payload: &(dyn Any + Send)) -> bool
+{
+    match payload.downcast_ref::<String>() {
+        Some(msg) => msg.starts_with("error["),
+        None => false,
     }
 }
 
-fn main()
+/// Parse and run a source file, producing the process exit code
+fn run_file(file_name: &str, max_depth: usize, vm: &mut VM) -> u8
+{
+    // Parse the source file
+    let prog = match panic::catch_unwind(|| parse_file(file_name, max_depth)) {
+        Ok(prog) => prog,
+        Err(payload) if is_diagnostic(&*payload) => return EXIT_COMPILE_ERROR,
+        Err(_) => return EXIT_INTERNAL_ERROR,
+    };
+    debug!("{:#?}", prog);
+
+    // Evaluate the program
+    let result = match panic::catch_unwind(AssertUnwindSafe(|| vm.eval(&prog))) {
+        Ok(result) => result,
+        Err(payload) if is_diagnostic(&*payload) => {
+            vm.print_stack_trace(&prog);
+            return EXIT_RUNTIME_ERROR;
+        }
+        Err(_) => return EXIT_INTERNAL_ERROR,
+    };
+
+    // The program's result becomes the process exit code
+    return match result {
+        Value::IntVal(int_val) if (0..=255).contains(&int_val) => int_val as u8,
+        Value::IntVal(int_val) => {
+            eprintln!("error[E0104]: program result must be between 0 and 255, got {}", int_val);
+            EXIT_RUNTIME_ERROR
+        }
+        _ => {
+            eprintln!("error[E0104]: program result must be an integer, got \"{}\"", result);
+            EXIT_RUNTIME_ERROR
+        }
+    };
+}
+
+/// Report errors raised with error!() as plain diagnostics, while
+/// other panics are internal errors that keep the default report.
+/// With RUST_BACKTRACE set, all panics keep the default report.
+fn install_panic_hook()
+{
+    let default_hook = panic::take_hook();
+    let want_backtrace = env::var("RUST_BACKTRACE").is_ok_and(|val| val != "0");
+
+    panic::set_hook(Box::new(move |info| {
+        match info.payload().downcast_ref::<String>() {
+            Some(msg) if is_diagnostic(info.payload()) && !want_backtrace => eprintln!("{}", msg.trim_end()),
+            _ => default_hook(info),
+        }
+    }));
+}
+
+fn main() -> ExitCode
 {
-    // Get the command-line arguments
-    let args: Vec<String> = env::args().collect();
+    install_panic_hook();
+
+    // Get the command-line arguments, separating out options
+    let mut args: Vec<String> = Vec::new();
+    let mut caps: Vec<Cap> = ALL_CAPS.iter().map(|(_, cap)| *cap).collect();
+    let mut thousands_sep = None;
+    let mut decimal_point = '.';
+    let mut report = false;
+    let mut max_depth = DEFAULT_MAX_DEPTH;
+
+    for arg in env::args()
+    {
+        // Capabilities the program is allowed to use, eg --caps=stdout
+        if let Some(cap_names) = arg.strip_prefix("--caps=") {
+            caps = match parse_caps(cap_names) {
+                Some(caps) => caps,
+                None => {
+                    eprintln!("invalid capability list \"{}\"", cap_names);
+                    return ExitCode::from(EXIT_COMPILE_ERROR);
+                }
+            };
+            continue;
+        }
+
+        // Separator to group digits by thousands when printing, eg --thousands-sep=,
+        if let Some(sep) = arg.strip_prefix("--thousands-sep=") {
+            let mut sep_chars = sep.chars();
+            thousands_sep = match (sep_chars.next(), sep_chars.next()) {
+                (Some(ch), None) => Some(ch),
+                _ => {
+                    eprintln!("thousands separator must be a single character");
+                    return ExitCode::from(EXIT_COMPILE_ERROR);
+                }
+            };
+            continue;
+        }
+
+        // Decimal point to print floats with, eg --decimal-point=,
+        if let Some(point) = arg.strip_prefix("--decimal-point=") {
+            let mut point_chars = point.chars();
+            decimal_point = match (point_chars.next(), point_chars.next()) {
+                (Some(ch), None) => ch,
+                _ => {
+                    eprintln!("decimal point must be a single character");
+                    return ExitCode::from(EXIT_COMPILE_ERROR);
+                }
+            };
+            continue;
+        }
+
+        // How deeply statements and expressions can nest, eg --max-depth=500
+        if let Some(depth) = arg.strip_prefix("--max-depth=") {
+            max_depth = match depth.parse() {
+                Ok(depth) => depth,
+                Err(_) => {
+                    eprintln!("invalid maximum nesting depth \"{}\"", depth);
+                    return ExitCode::from(EXIT_COMPILE_ERROR);
+                }
+            };
+            continue;
+        }
+
+        // Print a report of the run on stderr once done, eg --report=json
+        if let Some(format) = arg.strip_prefix("--report=") {
+            if format != "json" {
+                eprintln!("unsupported report format \"{}\"", format);
+                return ExitCode::from(EXIT_COMPILE_ERROR);
+            }
+            report = true;
+            continue;
+        }
+
+        args.push(arg);
+    }
+
+    // Print the instruction set reference
+    if args.len() == 2 && args[1] == "isa" {
+        print_isa();
+        return ExitCode::SUCCESS;
+    }
+
+    // Explain a diagnostic code
+    if args.len() == 3 && args[1] == "explain" {
+        if explain(&args[2]) {
+            return ExitCode::SUCCESS;
+        }
+
+        return ExitCode::from(EXIT_COMPILE_ERROR);
+    }
+
+    let mut vm = VM::with_caps(&caps);
+    vm.thousands_sep = thousands_sep;
+    vm.decimal_point = decimal_point;
+
+    if report {
+        vm.op_counts = Some(vec![0; ALL_OPS.len()]);
+    }
+
+    // Run a program while counting pairs of instructions executed in sequence
+    if args.len() == 3 && args[1] == "profile" {
+        vm.pair_counts = Some(HashMap::default());
+        let start_time = Instant::now();
+        let exit_status = run_file(&args[2], max_depth, &mut vm);
+        vm.print_pair_profile();
+
+        if report {
+            eprintln!("{}", vm.run_report(exit_status, start_time.elapsed()).to_json());
+        }
+
+        return ExitCode::from(exit_status);
+    }
 
     if args.len() == 2 {
-        // Parse the source file
-        let prog = parse_file(&args[1]);
-        debug!("{:#?}", prog);
+        let start_time = Instant::now();
+        let exit_status = run_file(&args[1], max_depth, &mut vm);
+
+        if report {
+            eprintln!("{}", vm.run_report(exit_status, start_time.elapsed()).to_json());
+        }
+
+        return ExitCode::from(exit_status);
+    }
+
+    println!("Usage: {} [options] <your_script.bas>", args[0]);
+    println!("       {} profile [options] <your_script.bas>", args[0]);
+    println!("       {} isa", args[0]);
+    println!("       {} explain <code>", args[0]);
+    println!();
+    println!("Options:");
+    println!("  --caps=stdin,stdout    capabilities the program is allowed to use");
+    println!("  --thousands-sep=CHAR   group digits by thousands when printing numbers");
+    println!("  --decimal-point=CHAR   decimal point to use when printing floats");
+    println!("  --max-depth=N          how deeply statements and expressions can nest");
+    println!("  --report=json          print a report of the run on stderr once done");
+    return ExitCode::from(EXIT_COMPILE_ERROR);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    thread_local! {
+        /// Values printed by the program under test
+        static OUTPUT: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Print to OUTPUT instead of stdout
+    fn capture_print(vm: &mut VM)
+    {
+        let val = vm.pop();
+        OUTPUT.with(|output| output.borrow_mut().push(val.to_string()));
+    }
 
-        // Evaluate the program
+    /// Parse a program and create a VM whose output is captured
+    fn setup(src: &str) -> (Program, VM)
+    {
+        let prog = parse_str(src.to_string(), "test.bas", DEFAULT_MAX_DEPTH);
         let mut vm = VM::new();
-        vm.eval(prog);
+        vm.set_host_fn("print", capture_print);
+        OUTPUT.with(|output| output.borrow_mut().clear());
+        (prog, vm)
+    }
 
-        return;
+    fn take_output() -> Vec<String>
+    {
+        OUTPUT.with(|output| output.replace(Vec::new()))
+    }
+
+    const PROGRAM: &str = "
+        fun fib(n)
+            if n < 2 then return n
+            return fib(n - 1) + fib(n - 2)
+        end
+        let total = 0
+        for i = 0 to 10
+            total += fib(i)
+            gosub show
+        end
+        return total
+        label show:
+        print [i, total]
+        return
+    ";
+
+    #[test]
+    fn paused_runs_match_single_run()
+    {
+        let (prog, mut vm) = setup(PROGRAM);
+        let result = vm.eval(&prog);
+        let output = take_output();
+        assert!(result.equals(&Value::IntVal(143)));
+        assert_eq!(output.len(), 11);
+
+        // Resuming after each pause continues where the run left off
+        for budget in 1..=7 {
+            vm.init(&prog);
+            let paused_result = loop {
+                if let RunState::Done(result) = vm.run_for(&prog, budget) {
+                    break result;
+                }
+            };
+
+            assert!(paused_result.equals(&result));
+            assert_eq!(take_output(), output);
+        }
+    }
+
+    /// Read a fixed value instead of asking on stdin
+    fn fake_read_int(vm: &mut VM)
+    {
+        vm.push(Value::IntVal(21));
     }
 
-    println!("Usage: {} <your_script.bas>", args[0]);
+    #[test]
+    fn overridden_host_fns_are_called()
+    {
+        let (prog, mut vm) = setup("
+            let x = read_int
+            print x * 2
+            print \"done\"
+        ");
+        vm.set_host_fn("read_int", fake_read_int);
+        vm.eval(&prog);
+        assert_eq!(take_output(), ["42", "done"]);
+    }
 }